@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+/// Mixed writes to one sub-db, each with its own level, committed atomically
+/// by `DbWrap::write_batch` in the order they were added.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        lv: u8,
+        force: bool,
+    },
+    Delete {
+        key: Vec<u8>,
+        lv: u8,
+        force: bool,
+    },
+    /// Delete every key with `start <= key < end`.
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+        lv: u8,
+        force: bool,
+    },
+    /// Append `value` to the current payload of `key`, or write it if absent.
+    Merge {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        lv: u8,
+        force: bool,
+    },
+}
+
+/// Level-check outcome of one `BatchOp`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteOutcome {
+    Written,
+    /// The key exists with a level the write can't override.
+    LevelConflict { existing: u8 },
+    /// The write policy refused the write.
+    Rejected { reason: String },
+    /// A range delete that kept these refused keys and deleted the others.
+    Partial { refused: Vec<Vec<u8>> },
+    /// The write couldn't be checked, e.g. the stored value is corrupt.
+    Failed { error: String },
+}
+
+impl BatchOp {
+    /// Key of a single-key operation.
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            BatchOp::Put { key, .. } | BatchOp::Delete { key, .. } | BatchOp::Merge { key, .. } => Some(key),
+            BatchOp::DeleteRange { .. } => None,
+        }
+    }
+}
+
+impl DbBatch {
+    pub fn new() -> Self {
+        DbBatch::default()
+    }
+
+    pub fn put<K: AsRef<[u8]>>(mut self, k: K, v: Vec<u8>, lv: u8, force: bool) -> Self {
+        self.ops.push(BatchOp::Put {
+            key: k.as_ref().to_vec(),
+            value: v,
+            lv,
+            force,
+        });
+        self
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(mut self, k: K, lv: u8, force: bool) -> Self {
+        self.ops.push(BatchOp::Delete {
+            key: k.as_ref().to_vec(),
+            lv,
+            force,
+        });
+        self
+    }
+
+    pub fn delete_range<K: AsRef<[u8]>>(mut self, start: K, end: K, lv: u8, force: bool) -> Self {
+        self.ops.push(BatchOp::DeleteRange {
+            start: start.as_ref().to_vec(),
+            end: end.as_ref().to_vec(),
+            lv,
+            force,
+        });
+        self
+    }
+
+    pub fn merge<K: AsRef<[u8]>>(mut self, k: K, v: Vec<u8>, lv: u8, force: bool) -> Self {
+        self.ops.push(BatchOp::Merge {
+            key: k.as_ref().to_vec(),
+            value: v,
+            lv,
+            force,
+        });
+        self
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -1,35 +1,539 @@
-use rocksdb::Options;
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct RocksdbOptions {
-    // TODO add more options for better usage
-    pub create_if_missing: bool,
-    pub atomic_flush: bool,
-    // default 2
-    pub log_file_num: Option<usize>,
-    // default 20M
-    pub log_file_size: Option<usize>,
-}
-
-impl Default for RocksdbOptions {
-    fn default() -> Self {
-        RocksdbOptions {
-            create_if_missing: true,
-            atomic_flush: true,
-            log_file_num: Some(2),
-            log_file_size: Some(20 * 1000 * 1000)
-        }
-    }
-}
-
-impl From<RocksdbOptions> for Options {
-    fn from(roc_opt: RocksdbOptions) -> Self {
-        let mut opt = Options::default();
-        opt.create_if_missing(roc_opt.create_if_missing);
-        opt.set_atomic_flush(roc_opt.atomic_flush);
-        opt.set_keep_log_file_num(roc_opt.log_file_num.unwrap_or(2));
-        opt.set_max_log_file_size(roc_opt.log_file_size.unwrap_or(20 * 1000 * 1000));
-        opt
-    }
-}
+use crate::error::{DbResult, DbWrapError};
+use rocksdb::{BlockBasedOptions, Cache, DBCompactionStyle, DBCompressionType, DataBlockIndexType, Env, Options};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+static SHARED: OnceLock<SharedResources> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RocksdbOptions {
+    pub create_if_missing: bool,
+    pub atomic_flush: bool,
+    // default 2
+    pub log_file_num: Option<usize>,
+    // default 20M
+    pub log_file_size: Option<usize>,
+    /// LRU block cache size in bytes.
+    #[serde(default)]
+    pub block_cache_size: Option<usize>,
+    /// Bloom filter bits per key, e.g. 10 for a ~1% false positive rate.
+    #[serde(default)]
+    pub bloom_filter_bits: Option<f64>,
+    /// Compression of every level, unless `compression_per_level` is set.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Compression of each level, starting at level 0.
+    #[serde(default)]
+    pub compression_per_level: Option<Vec<Compression>>,
+    /// Memtable size in bytes.
+    #[serde(default)]
+    pub write_buffer_size: Option<usize>,
+    /// Most memtables kept in memory, including the one being written.
+    #[serde(default)]
+    pub max_write_buffer_number: Option<i32>,
+    /// -1 keeps every table file open.
+    #[serde(default)]
+    pub max_open_files: Option<i32>,
+    #[serde(default)]
+    pub max_background_jobs: Option<i32>,
+    /// WAL size in bytes that forces memtables to be flushed.
+    #[serde(default)]
+    pub max_total_wal_size: Option<u64>,
+    /// How long archived WAL files are kept.
+    #[serde(default)]
+    pub wal_ttl_seconds: Option<u64>,
+    /// Size limit in MB of archived WAL files.
+    #[serde(default)]
+    pub wal_size_limit_mb: Option<u64>,
+    /// Sync table files in the background every this many bytes, 0 disables.
+    #[serde(default)]
+    pub bytes_per_sync: Option<u64>,
+    /// Sync the WAL in the background every this many bytes, 0 disables.
+    #[serde(default)]
+    pub wal_bytes_per_sync: Option<u64>,
+    #[serde(default)]
+    pub compaction_style: Option<CompactionStyle>,
+    /// Process-wide resources attached to every sub-db, see `SharedOptions`.
+    #[serde(default)]
+    pub shared: Option<SharedOptions>,
+}
+
+/// Memory and threads shared by every sub-db of the process, so they don't
+/// grow with the number of open paths.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SharedOptions {
+    /// Size in bytes of the LRU block cache used by all sub-dbs.
+    pub block_cache_size: Option<usize>,
+    /// Memtable budget in bytes of each RocksDB instance. rocksdb 0.21 can't
+    /// share a `WriteBufferManager` between instances, so the process-wide
+    /// bound is this times `max_open_dbs`, or exactly this in column-family
+    /// mode; the server refuses it without either.
+    pub write_buffer_budget: Option<usize>,
+    /// Threads of the shared low priority (compaction) pool.
+    pub background_threads: Option<i32>,
+    /// Threads of the shared high priority (flush) pool.
+    pub high_priority_background_threads: Option<i32>,
+}
+
+/// Block cache and `Env` created once per process from `SharedOptions`.
+pub struct SharedResources {
+    options: SharedOptions,
+    cache: Option<Cache>,
+    env: Env,
+}
+
+impl SharedResources {
+    /// Create the process-wide resources, or return the existing ones if they
+    /// were created from the same options.
+    pub fn init(options: &SharedOptions) -> DbResult<&'static SharedResources> {
+        if let Some(shared) = SHARED.get() {
+            return shared.check(options);
+        }
+        let mut env = Env::new()?;
+        if let Some(n) = options.background_threads {
+            env.set_background_threads(n);
+        }
+        if let Some(n) = options.high_priority_background_threads {
+            env.set_high_priority_background_threads(n);
+        }
+        let shared = SharedResources {
+            options: options.clone(),
+            cache: options.block_cache_size.map(Cache::new_lru_cache),
+            env,
+        };
+        // Lost a race with another caller, theirs is kept.
+        let _ = SHARED.set(shared);
+        SHARED.get().expect("shared resources are set").check(options)
+    }
+
+    /// The process-wide resources, if created.
+    pub fn global() -> Option<&'static SharedResources> {
+        SHARED.get()
+    }
+
+    pub fn options(&self) -> &SharedOptions {
+        &self.options
+    }
+
+    fn check(&'static self, options: &SharedOptions) -> DbResult<&'static SharedResources> {
+        if &self.options != options {
+            return Err(DbWrapError::InvalidConfig(format!(
+                "shared: already configured as {:?} in this process",
+                self.options
+            )));
+        }
+        Ok(self)
+    }
+}
+
+/// Smallest memtable RocksDB accepts.
+const MIN_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+/// Smallest `max_open_files` RocksDB accepts besides -1.
+const MIN_OPEN_FILES: i32 = 20;
+/// Block cache of `OptionProfile::PointLookup` without a shared one.
+const POINT_LOOKUP_CACHE_SIZE: usize = 64 * 1024 * 1024;
+/// Levels of an LSM tree with default options.
+const NUM_LEVELS: usize = 7;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    Snappy,
+    Zlib,
+    Bz2,
+    Lz4,
+    Lz4hc,
+    Zstd,
+}
+
+impl From<Compression> for DBCompressionType {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => DBCompressionType::None,
+            Compression::Snappy => DBCompressionType::Snappy,
+            Compression::Zlib => DBCompressionType::Zlib,
+            Compression::Bz2 => DBCompressionType::Bz2,
+            Compression::Lz4 => DBCompressionType::Lz4,
+            Compression::Lz4hc => DBCompressionType::Lz4hc,
+            Compression::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompactionStyle {
+    Level,
+    Universal,
+    Fifo,
+}
+
+impl From<CompactionStyle> for DBCompactionStyle {
+    fn from(style: CompactionStyle) -> Self {
+        match style {
+            CompactionStyle::Level => DBCompactionStyle::Level,
+            CompactionStyle::Universal => DBCompactionStyle::Universal,
+            CompactionStyle::Fifo => DBCompactionStyle::Fifo,
+        }
+    }
+}
+
+impl Default for RocksdbOptions {
+    fn default() -> Self {
+        RocksdbOptions {
+            create_if_missing: true,
+            atomic_flush: true,
+            log_file_num: Some(2),
+            log_file_size: Some(20 * 1000 * 1000),
+            block_cache_size: None,
+            bloom_filter_bits: None,
+            compression: None,
+            compression_per_level: None,
+            write_buffer_size: None,
+            max_write_buffer_number: None,
+            max_open_files: None,
+            max_background_jobs: None,
+            max_total_wal_size: None,
+            wal_ttl_seconds: None,
+            wal_size_limit_mb: None,
+            bytes_per_sync: None,
+            wal_bytes_per_sync: None,
+            compaction_style: None,
+            shared: None,
+        }
+    }
+}
+
+impl RocksdbOptions {
+    /// Check the values RocksDB would reject or silently adjust.
+    pub fn validate(&self) -> DbResult<()> {
+        let invalid = |field: &str, reason: String| Err(DbWrapError::InvalidConfig(format!("{}: {}", field, reason)));
+        if self.log_file_num == Some(0) {
+            return invalid("log_file_num", "must keep at least 1 log file".to_string());
+        }
+        if self.block_cache_size == Some(0) {
+            return invalid("block_cache_size", "must be positive, leave unset for the default cache".to_string());
+        }
+        if let Some(bits) = self.bloom_filter_bits {
+            if !(bits > 0.0 && bits <= 64.0) {
+                return invalid("bloom_filter_bits", format!("{} is not within (0, 64]", bits));
+            }
+        }
+        if let Some(levels) = &self.compression_per_level {
+            if levels.is_empty() || levels.len() > NUM_LEVELS {
+                return invalid(
+                    "compression_per_level",
+                    format!("needs 1 to {} entries, got {}", NUM_LEVELS, levels.len()),
+                );
+            }
+        }
+        if let Some(size) = self.write_buffer_size {
+            if size < MIN_WRITE_BUFFER_SIZE {
+                return invalid("write_buffer_size", format!("{} is below the minimum of 64 KiB", size));
+            }
+        }
+        if let Some(n) = self.max_write_buffer_number {
+            if n < 2 {
+                return invalid("max_write_buffer_number", format!("{} is below the minimum of 2", n));
+            }
+        }
+        if let Some(n) = self.max_open_files {
+            if n != -1 && n < MIN_OPEN_FILES {
+                return invalid(
+                    "max_open_files",
+                    format!("{} must be -1 (unlimited) or at least {}", n, MIN_OPEN_FILES),
+                );
+            }
+        }
+        if let Some(n) = self.max_background_jobs {
+            if n < 1 {
+                return invalid("max_background_jobs", format!("{} is below the minimum of 1", n));
+            }
+        }
+        if let Some(shared) = &self.shared {
+            if self.block_cache_size.is_some() && shared.block_cache_size.is_some() {
+                return invalid(
+                    "block_cache_size",
+                    "set either block_cache_size or shared.block_cache_size".to_string(),
+                );
+            }
+            if shared.block_cache_size == Some(0) {
+                return invalid("shared.block_cache_size", "must be positive".to_string());
+            }
+            if let Some(size) = shared.write_buffer_budget {
+                if size < MIN_WRITE_BUFFER_SIZE {
+                    return invalid("shared.write_buffer_budget", format!("{} is below the minimum of 64 KiB", size));
+                }
+            }
+            for (field, n) in [
+                ("shared.background_threads", shared.background_threads),
+                ("shared.high_priority_background_threads", shared.high_priority_background_threads),
+            ] {
+                if let Some(n) = n {
+                    if n < 1 {
+                        return invalid(field, format!("{} is below the minimum of 1", n));
+                    }
+                }
+            }
+        }
+        if self.compaction_style == Some(CompactionStyle::Fifo) && self.max_open_files.unwrap_or(-1) != -1 {
+            return invalid("max_open_files", "FIFO compaction needs -1 (unlimited)".to_string());
+        }
+        Ok(())
+    }
+
+    /// These options with `shared` taken from the base options `base`, which
+    /// they must not contradict since `SharedResources` are process-wide.
+    pub fn inherit_shared(&self, base: Option<&SharedOptions>) -> DbResult<RocksdbOptions> {
+        if self.shared.is_some() && self.shared.as_ref() != base {
+            return Err(DbWrapError::InvalidConfig(
+                "shared: must be unset or match the base options".to_string(),
+            ));
+        }
+        Ok(RocksdbOptions {
+            shared: base.cloned(),
+            ..self.clone()
+        })
+    }
+
+    /// Validate and convert into RocksDB `Options`, attaching the process-wide
+    /// `SharedResources` if `shared` is set.
+    pub fn to_options(&self) -> DbResult<Options> {
+        self.validate()?;
+        let shared = match &self.shared {
+            Some(shared) => Some(SharedResources::init(shared)?),
+            None => None,
+        };
+        Ok(self.clone().build(shared))
+    }
+
+    fn build(self, shared: Option<&SharedResources>) -> Options {
+        let mut opt = Options::default();
+        opt.create_if_missing(self.create_if_missing);
+        opt.set_atomic_flush(self.atomic_flush);
+        opt.set_keep_log_file_num(self.log_file_num.unwrap_or(2));
+        opt.set_max_log_file_size(self.log_file_size.unwrap_or(20 * 1000 * 1000));
+        let shared_cache = shared.and_then(|shared| shared.cache.as_ref());
+        if self.block_cache_size.is_some() || self.bloom_filter_bits.is_some() || shared_cache.is_some() {
+            let mut table = BlockBasedOptions::default();
+            if let Some(size) = self.block_cache_size {
+                table.set_block_cache(&Cache::new_lru_cache(size));
+            }
+            if let Some(cache) = shared_cache {
+                table.set_block_cache(cache);
+            }
+            if let Some(bits) = self.bloom_filter_bits {
+                table.set_bloom_filter(bits, false);
+            }
+            opt.set_block_based_table_factory(&table);
+        }
+        if let Some(compression) = self.compression {
+            opt.set_compression_type(compression.into());
+        }
+        if let Some(levels) = self.compression_per_level {
+            let levels: Vec<DBCompressionType> = levels.into_iter().map(Into::into).collect();
+            opt.set_compression_per_level(&levels);
+        }
+        if let Some(size) = self.write_buffer_size {
+            opt.set_write_buffer_size(size);
+        }
+        if let Some(n) = self.max_write_buffer_number {
+            opt.set_max_write_buffer_number(n);
+        }
+        if let Some(n) = self.max_open_files {
+            opt.set_max_open_files(n);
+        }
+        if let Some(n) = self.max_background_jobs {
+            opt.set_max_background_jobs(n);
+        }
+        if let Some(size) = self.max_total_wal_size {
+            opt.set_max_total_wal_size(size);
+        }
+        if let Some(secs) = self.wal_ttl_seconds {
+            opt.set_wal_ttl_seconds(secs);
+        }
+        if let Some(size) = self.wal_size_limit_mb {
+            opt.set_wal_size_limit_mb(size);
+        }
+        if let Some(n) = self.bytes_per_sync {
+            opt.set_bytes_per_sync(n);
+        }
+        if let Some(n) = self.wal_bytes_per_sync {
+            opt.set_wal_bytes_per_sync(n);
+        }
+        if let Some(style) = self.compaction_style {
+            opt.set_compaction_style(style.into());
+        }
+        if let Some(shared) = shared {
+            opt.set_env(&shared.env);
+            if let Some(size) = shared.options.write_buffer_budget {
+                opt.set_db_write_buffer_size(size);
+            }
+        }
+        opt
+    }
+}
+
+/// Converts without validation nor `shared` resources, see
+/// `RocksdbOptions::to_options`.
+impl From<RocksdbOptions> for Options {
+    fn from(roc_opt: RocksdbOptions) -> Self {
+        roc_opt.build(None)
+    }
+}
+
+/// Named tunings applied on top of a sub-db's options.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OptionProfile {
+    /// Large memtables and up to 4 background jobs for log-like sub-dbs.
+    WriteHeavy,
+    /// Block cache and bloom filters for small, hot lookup tables. Uses the
+    /// shared block cache if there is one, else a 64 MiB cache of its own.
+    PointLookup,
+    /// Strong compression and universal compaction for rarely read data.
+    BulkArchive,
+}
+
+impl OptionProfile {
+    pub fn apply(self, opt: &mut Options) {
+        match self {
+            OptionProfile::WriteHeavy => {
+                opt.set_write_buffer_size(128 * 1024 * 1024);
+                opt.set_max_write_buffer_number(4);
+                opt.set_min_write_buffer_number_to_merge(2);
+                opt.set_level_zero_file_num_compaction_trigger(8);
+                // Not `increase_parallelism`, which resizes the thread pools
+                // of the process-wide Env shared by every sub-db.
+                opt.set_max_background_jobs(4);
+            }
+            // Same as RocksDB's `OptimizeForPointLookup`, which always
+            // creates its own block cache.
+            OptionProfile::PointLookup => {
+                let mut table = BlockBasedOptions::default();
+                match SharedResources::global().and_then(|shared| shared.cache.as_ref()) {
+                    Some(cache) => table.set_block_cache(cache),
+                    None => table.set_block_cache(&Cache::new_lru_cache(POINT_LOOKUP_CACHE_SIZE)),
+                }
+                table.set_data_block_index_type(DataBlockIndexType::BinaryAndHash);
+                table.set_data_block_hash_ratio(0.75);
+                table.set_bloom_filter(10.0, false);
+                opt.set_block_based_table_factory(&table);
+                opt.set_memtable_prefix_bloom_ratio(0.02);
+                opt.set_memtable_whole_key_filtering(true);
+            }
+            OptionProfile::BulkArchive => {
+                opt.set_compaction_style(DBCompactionStyle::Universal);
+                opt.set_compression_type(DBCompressionType::Zstd);
+                opt.set_bottommost_compression_type(DBCompressionType::Zstd);
+                opt.set_write_buffer_size(16 * 1024 * 1024);
+            }
+        }
+    }
+}
+
+/// How `DbWrap` opens its sub-dbs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OpenMode {
+    ReadWrite,
+    /// Read the sub-dbs as of when they were opened, even while another
+    /// process holds their write lock.
+    ReadOnly,
+    /// Follow a primary process writing the same sub-dbs, see
+    /// `DbWrap::catch_up`. Each sub-db keeps its secondary logs under
+    /// `secondary_path`; RocksDB requires `max_open_files` to be -1.
+    Secondary { secondary_path: String },
+}
+
+impl Default for OpenMode {
+    fn default() -> Self {
+        OpenMode::ReadWrite
+    }
+}
+
+/// Options override for the sub-dbs whose path matches `pattern`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PathOptions {
+    /// Sub-db path or glob: `*` matches within one path segment, `**` across
+    /// segments and `?` a single character.
+    pub pattern: String,
+    pub profile: Option<OptionProfile>,
+    /// Replaces the base options, if set. They inherit the base `shared`
+    /// resources and may not configure others.
+    pub options: Option<RocksdbOptions>,
+}
+
+impl PathOptions {
+    /// Resolve the options of matching sub-dbs, `base` being the options of
+    /// sub-dbs without an override and `shared` their shared resources.
+    pub fn to_options(&self, base: &Options, shared: Option<&SharedOptions>) -> DbResult<Options> {
+        let mut opt = match &self.options {
+            Some(roc_opt) => roc_opt.inherit_shared(shared)?.to_options()?,
+            None => base.clone(),
+        };
+        if let Some(profile) = self.profile {
+            profile.apply(&mut opt);
+        }
+        Ok(opt)
+    }
+}
+
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    fn matches(p: &[u8], s: &[u8]) -> bool {
+        match p.split_first() {
+            None => s.is_empty(),
+            Some((b'*', rest)) if rest.first() == Some(&b'*') => {
+                (0..=s.len()).any(|i| matches(&rest[1..], &s[i..]))
+            }
+            Some((b'*', rest)) => (0..=s.len())
+                .take_while(|&i| i == 0 || s[i - 1] != b'/')
+                .any(|i| matches(rest, &s[i..])),
+            Some((b'?', rest)) => !s.is_empty() && s[0] != b'/' && matches(rest, &s[1..]),
+            Some((c, rest)) => s.first() == Some(c) && matches(rest, &s[1..]),
+        }
+    }
+    matches(pattern.as_bytes(), path.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(glob_match("users", "users"));
+        assert!(!glob_match("users", "users2"));
+        assert!(!glob_match("users", "user"));
+        assert!(glob_match("", ""));
+    }
+
+    #[test]
+    fn star_stays_within_a_segment() {
+        assert!(glob_match("logs/*", "logs/2024"));
+        assert!(glob_match("logs/*", "logs/"));
+        assert!(!glob_match("logs/*", "logs/2024/01"));
+        assert!(glob_match("*/users", "tenant/users"));
+        assert!(!glob_match("*/users", "a/b/users"));
+        assert!(glob_match("a*c", "abbc"));
+        assert!(!glob_match("a*c", "ab/c"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert!(glob_match("logs/**", "logs/2024/01"));
+        assert!(glob_match("logs/**", "logs/"));
+        assert!(glob_match("**/users", "a/b/users"));
+        assert!(glob_match("**", "any/depth/at/all"));
+        assert!(!glob_match("logs/**", "metrics/2024"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_non_separator() {
+        assert!(glob_match("db?", "db1"));
+        assert!(!glob_match("db?", "db"));
+        assert!(!glob_match("db?", "db12"));
+        assert!(!glob_match("a?b", "a/b"));
+    }
+}
@@ -1,40 +1,42 @@
-use anyhow::{anyhow, bail, Result};
+use crate::batch::*;
+use crate::config::{glob_match, OpenMode};
+use crate::error::{DbResult, DbWrapError};
+use crate::header::*;
+use crate::iter::*;
+use crate::lock::KeyLocks;
+use crate::name::SubDbName;
+use crate::policy::*;
+use crate::registry::{PoolMetrics, Registry};
+use crate::snapshot::DbSnapshot;
+use crate::subdb::*;
 use parking_lot::RwLock;
-use rocksdb::{Options, WriteBatch, DB};
-use std::collections::HashMap;
+use rocksdb::{ColumnFamilyDescriptor, Options, ReadOptions, WriteBatch, DB, DEFAULT_COLUMN_FAMILY_NAME};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 const DEFAULT_LEVEL: u8 = 3;
-const SEG: u8 = '\n' as u8;
+const MIGRATION_BATCH: usize = 1024;
 
 pub trait DataLevel
 where
     Self: AsRef<[u8]>,
 {
-    fn data_lv(&self) -> u8 {
-        let d = self.as_ref();
-        if d.len() < 3 {
-            DEFAULT_LEVEL
-        } else if d[0] == SEG && d[2] == SEG {
-            d[1]
-        } else {
-            DEFAULT_LEVEL
-        }
+    fn data_lv(&self) -> DbResult<u8> {
+        Ok(split_level(self.as_ref())?.1)
     }
 
     fn data_with_lv(&self, lv: u8) -> Vec<u8> {
-        vec![[SEG, lv, SEG].as_ref(), self.as_ref()].concat()
+        encode_value(self.as_ref(), lv)
     }
 
-    fn data_without_lv(&self) -> Vec<u8> {
-        let d = self.as_ref();
-        if d.len() < 3 {
-            d.to_vec()
-        } else if d[0] == SEG && d[2] == SEG {
-            d[3..].to_vec()
-        } else {
-            d.to_vec()
-        }
+    fn data_without_lv(&self) -> DbResult<Vec<u8>> {
+        Ok(split_level(self.as_ref())?.0.to_vec())
     }
 }
 
@@ -44,10 +46,117 @@ impl DataLevel for Vec<u8> {}
 
 impl DataLevel for &Vec<u8> {}
 
+/// Inclusive bounds on the `DataLevel` of values returned by a scan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelFilter {
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+}
+
+impl LevelFilter {
+    pub fn new(min: Option<u8>, max: Option<u8>) -> Self {
+        LevelFilter { min, max }
+    }
+
+    pub fn contains(&self, lv: u8) -> bool {
+        self.min.map_or(true, |min| lv >= min) && self.max.map_or(true, |max| lv <= max)
+    }
+}
+
+/// Number of values and their payload bytes stored at one `DataLevel`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Level-checked writes to several sub-dbs, committed all or nothing by
+/// `DbWrap::write_multi_path`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultiPathBatch {
+    ops: Vec<MultiPathOp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MultiPathOp {
+    Put {
+        path: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        lv: u8,
+        force: bool,
+    },
+    Delete {
+        path: String,
+        key: Vec<u8>,
+        lv: u8,
+        force: bool,
+    },
+}
+
+impl MultiPathOp {
+    pub fn path(&self) -> &str {
+        match self {
+            MultiPathOp::Put { path, .. } | MultiPathOp::Delete { path, .. } => path,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            MultiPathOp::Put { key, .. } | MultiPathOp::Delete { key, .. } => key,
+        }
+    }
+}
+
+impl MultiPathBatch {
+    pub fn new() -> Self {
+        MultiPathBatch::default()
+    }
+
+    pub fn put<K: AsRef<[u8]>>(mut self, path: &str, k: K, v: Vec<u8>, lv: u8, force: bool) -> Self {
+        self.ops.push(MultiPathOp::Put {
+            path: path.to_string(),
+            key: k.as_ref().to_vec(),
+            value: v,
+            lv,
+            force,
+        });
+        self
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(mut self, path: &str, k: K, lv: u8, force: bool) -> Self {
+        self.ops.push(MultiPathOp::Delete {
+            path: path.to_string(),
+            key: k.as_ref().to_vec(),
+            lv,
+            force,
+        });
+        self
+    }
+
+    pub fn ops(&self) -> &[MultiPathOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
 pub struct DbWrap {
     path: String,
     opt: Options,
-    dbs: RwLock<HashMap<String, Arc<DB>>>,
+    path_opts: Vec<(String, Options)>,
+    dbs: Registry,
+    cfs: Option<CfStore>,
+    mode: OpenMode,
+    policy: Arc<dyn WritePolicy>,
+    path_policies: RwLock<HashMap<String, Arc<dyn WritePolicy>>>,
+    locks: KeyLocks,
 }
 
 impl DbWrap {
@@ -55,155 +164,1171 @@ impl DbWrap {
         DbWrap {
             path: path.to_string(),
             opt,
-            dbs: RwLock::new(HashMap::new()),
+            path_opts: vec![],
+            dbs: Registry::default(),
+            cfs: None,
+            mode: OpenMode::ReadWrite,
+            policy: Arc::new(LevelPolicy),
+            path_policies: RwLock::new(HashMap::new()),
+            locks: KeyLocks::default(),
+        }
+    }
+
+    /// Open the sub-dbs whose path matches the glob `pattern` with `opt`.
+    /// The first matching pattern wins, in the order they were added.
+    pub fn with_path_options(mut self, pattern: &str, opt: Options) -> Self {
+        self.path_opts.push((pattern.to_string(), opt));
+        self
+    }
+
+    /// Options a sub-db at `path` is opened with.
+    pub fn options_for(&self, path: &str) -> &Options {
+        self.path_opts
+            .iter()
+            .find(|(pattern, _)| glob_match(pattern, path))
+            .map_or(&self.opt, |(_, opt)| opt)
+    }
+
+    /// Keep at most `max` sub-dbs open, closing the least recently used idle
+    /// ones when more are needed.
+    pub fn with_max_open_dbs(mut self, max: usize) -> Self {
+        self.dbs.set_max_open(Some(max));
+        self
+    }
+
+    /// Store every sub-db as a column family of a single RocksDB instance at
+    /// the root path instead of as its own instance below it.
+    ///
+    /// Sub-dbs then share one WAL and set of background threads, and
+    /// `write_multi_path` can update several of them atomically. Column
+    /// families are created on first use with the sub-db's `options_for`.
+    pub fn with_column_families(mut self) -> Self {
+        self.cfs = Some(CfStore::default());
+        self
+    }
+
+    /// Open sub-dbs read-only or as secondaries of another process instead of
+    /// for writing. Write methods then fail with `DbWrapError::ReadOnly`.
+    ///
+    /// Secondaries need `max_open_files` to be -1 in every option set, which
+    /// `Options` can't be checked for here; opening fails otherwise.
+    pub fn with_open_mode(mut self, mode: OpenMode) -> Self {
+        self.dbs.set_read_only(mode != OpenMode::ReadWrite);
+        self.mode = mode;
+        self
+    }
+
+    pub fn open_mode(&self) -> &OpenMode {
+        &self.mode
+    }
+
+    /// How long a level-checked write waits in total for its keys before
+    /// failing with a retryable `WriteConflict`. Writes wait indefinitely for
+    /// timeouts too large to represent as a deadline, such as `Duration::MAX`.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.locks.set_timeout(timeout);
+        self
+    }
+
+    /// Replace the default write policy used by sub-dbs without their own one.
+    pub fn with_write_policy<P: WritePolicy + 'static>(mut self, policy: P) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Use `policy` for writes to the sub-db at `path` only.
+    pub fn set_path_write_policy<P: WritePolicy + 'static>(&self, path: &str, policy: P) {
+        self.path_policies.write().insert(path.to_string(), Arc::new(policy));
+    }
+
+    /// Drop the per-path policy of `path`, falling back to the default one.
+    pub fn remove_path_write_policy(&self, path: &str) {
+        self.path_policies.write().remove(path);
+    }
+
+    pub fn write_policy(&self, path: &str) -> Arc<dyn WritePolicy> {
+        match self.path_policies.read().get(path) {
+            Some(policy) => policy.clone(),
+            None => self.policy.clone(),
         }
     }
 
-    pub fn db(&self, path: &str) -> Result<Arc<DB>> {
-        let path = self.path.clone() + "/" + path;
-        let mut dbs = self.dbs.write();
-        let db = match dbs.get(&path) {
-            Some(db) => db.clone(),
-            None => match DB::open(&self.opt, &path) {
-                Ok(db) => {
-                    let db = Arc::new(db);
-                    dbs.insert(path.into(), db.clone());
-                    db
+    /// RocksDB instance of the sub-db at `path`.
+    ///
+    /// Not available in column-family mode, where sub-dbs share one instance.
+    pub fn db(&self, path: &str) -> DbResult<Arc<DB>> {
+        if self.cfs.is_some() {
+            return Err(DbWrapError::Unsupported(
+                "sub-dbs have no instance of their own in column-family mode".to_string(),
+            ));
+        }
+        let name = SubDbName::new(path)?;
+        self.dbs.get_or_open(path, || {
+            let path = self.disk_path(&name);
+            let opt = self.options_for(name.as_str());
+            let db = match &self.mode {
+                OpenMode::ReadWrite => DB::open(opt, &path),
+                OpenMode::ReadOnly => DB::open_for_read_only(opt, &path, false),
+                OpenMode::Secondary { secondary_path } => {
+                    let secondary = secondary_path.clone() + "/" + name.as_str();
+                    // RocksDB only creates the last directory of the path.
+                    create_parent_dir(&secondary)?;
+                    DB::open_as_secondary(opt, &path, &secondary)
                 }
-                Err(e) => bail!("{}", e.to_string()),
-            },
+            };
+            db.map_err(|e| DbWrapError::OpenFailed { path, source: e })
+        })
+    }
+
+    fn sub_db(&self, path: &str) -> DbResult<SubDb> {
+        let cfs = match &self.cfs {
+            Some(cfs) => cfs,
+            None => return Ok(SubDb::Db(self.db(path)?)),
         };
+        let name = SubDbName::new(path)?;
+        let db = self.cf_db(cfs)?;
+        if self.mode == OpenMode::ReadWrite {
+            cfs.ensure_cf(&db, name.as_str(), self.options_for(name.as_str()))?;
+        } else {
+            column_family(&db, name.as_str())?;
+        }
+        Ok(SubDb::Cf(db, name.as_str().to_string()))
+    }
 
-        Ok(db)
+    /// Like `sub_db`, failing with `DbWrapError::ReadOnly` unless opened for writing.
+    fn writable_db(&self, path: &str) -> DbResult<SubDb> {
+        self.check_writable(path)?;
+        self.sub_db(path)
     }
 
-    pub fn flush(&self, path: &str) -> Result<()> {
-        self.db(path)?.flush()?;
-        Ok(())
+    fn check_writable(&self, path: &str) -> DbResult<()> {
+        match self.mode {
+            OpenMode::ReadWrite => Ok(()),
+            _ => Err(DbWrapError::ReadOnly(path.to_string())),
+        }
     }
 
-    pub fn get<K: AsRef<[u8]>>(&self, k: K, path: &str) -> Result<Option<Vec<u8>>> {
-        let db = self.db(path)?;
-        let value = match db.get(k) {
-            Ok(value) => value,
-            Err(e) => {
-                bail!("database get error: {e:?}");
+    fn cf_db(&self, cfs: &CfStore) -> DbResult<Arc<CfDb>> {
+        cfs.get_or_open(|| {
+            let mut opt = self.opt.clone();
+            opt.create_missing_column_families(true);
+            let cfs = self.list_cfs()?.into_iter().map(|name| {
+                let cf_opt = self.options_for(&name).clone();
+                ColumnFamilyDescriptor::new(name, cf_opt)
+            });
+            let db = match &self.mode {
+                OpenMode::ReadWrite => CfDb::open_cf_descriptors(&opt, &self.path, cfs),
+                OpenMode::ReadOnly => CfDb::open_cf_descriptors_read_only(&opt, &self.path, cfs, false),
+                OpenMode::Secondary { secondary_path } => {
+                    create_parent_dir(secondary_path)?;
+                    CfDb::open_cf_descriptors_as_secondary(&opt, &self.path, secondary_path, cfs)
+                }
+            };
+            db.map_err(|e| DbWrapError::OpenFailed {
+                path: self.path.clone(),
+                source: e,
+            })
+        })
+    }
+
+    /// Sub-db column families of the instance at the root path, sorted.
+    fn list_cfs(&self) -> DbResult<Vec<String>> {
+        if !Path::new(&self.path).join("CURRENT").is_file() {
+            return Ok(vec![]);
+        }
+        let mut names: Vec<String> = CfDb::list_cf(&self.opt, &self.path)?
+            .into_iter()
+            .filter(|name| name != DEFAULT_COLUMN_FAMILY_NAME)
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn disk_path(&self, name: &SubDbName) -> String {
+        self.path.clone() + "/" + name.as_str()
+    }
+
+    /// Sub-dbs currently open in this `DbWrap`.
+    pub fn list_open(&self) -> Vec<String> {
+        match &self.cfs {
+            // Every column family is open along with the instance.
+            Some(cfs) if cfs.get().is_some() => self.list_cfs().unwrap_or_default(),
+            Some(_) => vec![],
+            None => self.dbs.list(),
+        }
+    }
+
+    /// Opens and evictions of the sub-db handle pool.
+    pub fn pool_metrics(&self) -> PoolMetrics {
+        self.dbs.metrics()
+    }
+
+    /// Sub-dbs found on disk under the root path, whether open or not.
+    pub fn list_on_disk(&self) -> DbResult<Vec<String>> {
+        if self.cfs.is_some() {
+            return self.list_cfs();
+        }
+        let mut paths = vec![];
+        let root = Path::new(&self.path);
+        if root.is_dir() {
+            find_sub_dbs(root, root, &mut paths)?;
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Flush and close the sub-db at `path`, returning whether it was open.
+    ///
+    /// Fails with `DbWrapError::InUse` while handles from `db` are still alive.
+    /// In column-family mode the column family is only flushed, it stays open
+    /// with the instance.
+    pub fn close(&self, path: &str) -> DbResult<bool> {
+        SubDbName::new(path)?;
+        if let Some(cfs) = &self.cfs {
+            let db = match cfs.get() {
+                Some(db) => db,
+                None => return Ok(false),
+            };
+            let cf = match db.cf_handle(path) {
+                Some(cf) => cf,
+                None => return Ok(false),
+            };
+            db.flush_cf(&cf)?;
+            return Ok(true);
+        }
+        let was_open = self.dbs.list().iter().any(|p| p == path);
+        self.dbs.exclusive(&[path], || Ok(()))?;
+        Ok(was_open)
+    }
+
+    /// Close the sub-db at `path` and delete its files.
+    ///
+    /// Fails with `DbWrapError::InUse` while sub-dbs nested below `path`
+    /// exist, open or on disk.
+    pub fn destroy(&self, path: &str) -> DbResult<()> {
+        let name = SubDbName::new(path)?;
+        self.check_writable(path)?;
+        if let Some(cfs) = &self.cfs {
+            let db = self.cf_db(cfs)?;
+            cfs.drop_cf(&db, path)?;
+            return Ok(());
+        }
+        self.dbs.exclusive(&[path], || {
+            self.check_no_nested(&name)?;
+            Ok(DB::destroy(self.options_for(path), self.disk_path(&name))?)
+        })
+    }
+
+    /// Close the sub-db at `from` and move it to `to`, which must not exist.
+    /// Fails with `DbWrapError::InUse` like `destroy` while sub-dbs are
+    /// nested below `from`.
+    ///
+    /// Column families can't be renamed, so this fails in column-family mode.
+    pub fn rename(&self, from: &str, to: &str) -> DbResult<()> {
+        let (from_name, to_name) = (SubDbName::new(from)?, SubDbName::new(to)?);
+        self.check_writable(from)?;
+        if self.cfs.is_some() {
+            return Err(DbWrapError::Unsupported(
+                "column families can't be renamed".to_string(),
+            ));
+        }
+        self.dbs.exclusive(&[from, to], || {
+            self.check_no_nested(&from_name)?;
+            let (from, to) = (self.disk_path(&from_name), self.disk_path(&to_name));
+            if !Path::new(&from).is_dir() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", from)).into());
             }
-        };
-        match value {
-            Some(v) => {
-                let v = v.data_without_lv();
-                Ok(Some(v))
+            if Path::new(&to).exists() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to)).into());
             }
-            None => Ok(None),
+            create_parent_dir(&to)?;
+            Ok(fs::rename(from, to)?)
+        })
+    }
+
+    /// Fail with `DbWrapError::InUse` if a sub-db below `name` is open, being
+    /// opened or on disk, as moving or deleting `name` would take it along.
+    fn check_no_nested(&self, name: &SubDbName) -> DbResult<()> {
+        if let Some(path) = self.dbs.nested(name.as_str()).into_iter().next() {
+            return Err(DbWrapError::InUse(path));
+        }
+        let dir = self.disk_path(name);
+        let mut nested = vec![];
+        if Path::new(&dir).is_dir() {
+            find_sub_dbs(Path::new(&self.path), Path::new(&dir), &mut nested)?;
+        }
+        match nested.into_iter().next() {
+            Some(path) => Err(DbWrapError::InUse(path)),
+            None => Ok(()),
         }
     }
 
-    pub fn put<K: AsRef<[u8]>>(&self, k: K, v: Vec<u8>, lv: u8, force: bool, path: &str) -> Result<()> {
-        let db = self.db(path)?;
-        match db.get(&k) {
-            Ok(Some(old)) => {
-                if old.data_lv() < lv && !force {
-                    bail!("can't put data with level {lv} which exist with DataLevel {} without force", old.data_lv());
+    /// Replay the latest writes of the primary into the sub-db at `path`.
+    /// Only available in secondary mode; in column-family mode every sub-db
+    /// catches up at once.
+    pub fn catch_up(&self, path: &str) -> DbResult<()> {
+        self.check_secondary()?;
+        self.sub_db(path)?.catch_up()
+    }
+
+    /// `catch_up` every open sub-db.
+    pub fn catch_up_all(&self) -> DbResult<()> {
+        self.check_secondary()?;
+        if let Some(cfs) = &self.cfs {
+            if let Some(db) = cfs.get() {
+                db.try_catch_up_with_primary()?;
+            }
+            return Ok(());
+        }
+        for db in self.dbs.handles() {
+            db.try_catch_up_with_primary()?;
+        }
+        Ok(())
+    }
+
+    /// Call `catch_up_all` every `period` on a background thread, until `db`
+    /// is dropped.
+    pub fn spawn_catch_up(db: &Arc<DbWrap>, period: Duration) -> thread::JoinHandle<()> {
+        let db = Arc::downgrade(db);
+        thread::spawn(move || loop {
+            thread::sleep(period);
+            match db.upgrade() {
+                // A failed catch-up is retried on the next period.
+                Some(db) => {
+                    let _ = db.catch_up_all();
                 }
+                None => return,
             }
-            Err(e) => {
-                bail!("database put-check error: {:?}", e);
+        })
+    }
+
+    fn check_secondary(&self) -> DbResult<()> {
+        match self.mode {
+            OpenMode::Secondary { .. } => Ok(()),
+            _ => Err(DbWrapError::Unsupported("catching up needs secondary mode".to_string())),
+        }
+    }
+
+    pub fn flush(&self, path: &str) -> DbResult<()> {
+        self.writable_db(path)?.flush()
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, k: K, path: &str) -> DbResult<Option<Vec<u8>>> {
+        Ok(self.get_with_level(k, path)?.map(|(v, _)| v))
+    }
+
+    /// Like `get`, but also returns the `DataLevel` the value was written with.
+    pub fn get_with_level<K: AsRef<[u8]>>(&self, k: K, path: &str) -> DbResult<Option<(Vec<u8>, u8)>> {
+        let db = self.sub_db(path)?;
+        match db.get(k.as_ref())? {
+            Some(v) => {
+                let (payload, lv) = split_level(&v)?;
+                Ok(Some((payload.to_vec(), lv)))
             }
-            Ok(None) => (),
-        };
-        db.put(k, &v.data_with_lv(lv))?;
-        Ok(())
+            None => Ok(None),
+        }
+    }
+
+    /// Values of `keys` in the same order, `None` for missing keys, read with
+    /// one batched RocksDB lookup.
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: Vec<K>, path: &str) -> DbResult<Vec<Option<Vec<u8>>>> {
+        let values = self.sub_db(path)?.multi_get_opt(&keys, &ReadOptions::default())?;
+        strip_levels(values)
+    }
+
+    pub fn put<K: AsRef<[u8]>>(&self, k: K, v: Vec<u8>, lv: u8, force: bool, path: &str) -> DbResult<()> {
+        self.put_batch(vec![(k, v)], lv, force, path)
     }
 
+    /// Level-check and write all `pairs` atomically, holding their key locks
+    /// so concurrent writers can't interleave between check and write.
+    ///
+    /// Fails with a retryable `WriteConflict` if the keys stay locked for
+    /// longer than the lock timeout.
     pub fn put_batch<K: AsRef<[u8]>>(
         &self,
         pairs: Vec<(K, Vec<u8>)>,
         lv: u8,
         force: bool,
         path: &str,
-    ) -> Result<()> {
-        let db = self.db(path)?;
-        let mut batch = WriteBatch::default();
+    ) -> DbResult<()> {
+        let db = self.writable_db(path)?;
+        let policy = self.write_policy(path);
+        let _guard = self.locks.lock(path, pairs.iter().map(|(k, _)| k.as_ref()))?;
+        let mut staged = Staged::new(&*policy, path, &db)?;
         for (k, v) in pairs {
-            match db.get(&k) {
-                Ok(Some(old)) => {
-                    if old.data_lv() < lv && !force {
-                        bail!("can't put data with level {lv} which exist with DataLevel {} without force", old.data_lv());
+            let outcome = staged.write(k.as_ref(), Some(v), lv, force)?;
+            ensure_written(outcome, k.as_ref(), lv)?;
+        }
+        db.write(staged.batch)?;
+        Ok(())
+    }
+
+    /// Level-check every operation of `batch` in order and atomically write
+    /// the admitted ones, returning one outcome per operation. Refused
+    /// operations are skipped without failing the batch.
+    ///
+    /// Range deletes are level-checked key by key over the keys found when
+    /// the batch starts. A batch with range deletes locks the whole sub-db.
+    ///
+    /// An operation that can't be checked, e.g. over a corrupt stored value,
+    /// yields `WriteOutcome::Failed`, or keeps that key as refused for a
+    /// range delete.
+    pub fn write_batch(&self, batch: DbBatch, path: &str) -> DbResult<Vec<WriteOutcome>> {
+        let db = self.writable_db(path)?;
+        let policy = self.write_policy(path);
+        // Keys of a range are only known once scanned, so a batch with ranges
+        // scans and writes under the sub-db lock to not miss keys written meanwhile.
+        let ranged = batch.ops().iter().any(|op| matches!(op, BatchOp::DeleteRange { .. }));
+        let _guard = if ranged {
+            self.locks.lock_path(path)?
+        } else {
+            self.locks.lock(path, batch.ops().iter().filter_map(|op| op.key()))?
+        };
+        let mut range_keys = Vec::with_capacity(batch.len());
+        for op in batch.ops() {
+            range_keys.push(match op {
+                BatchOp::DeleteRange { start, end, .. } => self.search_keys(start, Some(end.clone()), &db)?,
+                _ => vec![],
+            });
+        }
+
+        let mut staged = Staged::new(&*policy, path, &db)?;
+        let mut outcomes = Vec::with_capacity(batch.len());
+        for (op, range) in batch.ops().iter().zip(range_keys) {
+            let outcome = match op {
+                BatchOp::Put { key, value, lv, force } => staged.write(key, Some(value.clone()), *lv, *force),
+                BatchOp::Delete { key, lv, force } => staged.write(key, None, *lv, *force),
+                BatchOp::Merge { key, value, lv, force } => staged.current_payload(key).and_then(|payload| {
+                    let mut merged = payload.unwrap_or_default();
+                    merged.extend_from_slice(value);
+                    staged.write(key, Some(merged), *lv, *force)
+                }),
+                BatchOp::DeleteRange { start, end, lv, force } => {
+                    // Keys written earlier in the batch count as well.
+                    let mut keys: BTreeSet<Vec<u8>> = range.into_iter().collect();
+                    keys.extend(
+                        staged
+                            .pending
+                            .keys()
+                            .filter(|k| k.as_slice() >= start.as_slice() && k.as_slice() < end.as_slice())
+                            .cloned(),
+                    );
+                    let mut refused = vec![];
+                    for key in keys {
+                        let written = match staged.current(&key) {
+                            Ok(None) => continue,
+                            Ok(Some(_)) => staged.write(&key, None, *lv, *force),
+                            Err(e) => Err(e),
+                        };
+                        if !matches!(written, Ok(WriteOutcome::Written)) {
+                            refused.push(key);
+                        }
+                    }
+                    if refused.is_empty() {
+                        Ok(WriteOutcome::Written)
+                    } else {
+                        Ok(WriteOutcome::Partial { refused })
                     }
                 }
-                Err(e) => {
-                    bail!("database put-check error: {:?}", e);
-                }
-                Ok(None) => (),
             };
-            batch.put(k, &v.data_with_lv(lv));
+            outcomes.push(outcome.unwrap_or_else(|e| WriteOutcome::Failed { error: e.to_string() }));
         }
-        db.write(batch).map_err(|e| anyhow!("{:?}", e))?;
-        Ok(())
+        db.write(staged.batch)?;
+        Ok(outcomes)
     }
 
-    pub fn delete<K: AsRef<[u8]>>(&self, k: K, path: &str) -> Result<()> {
-        let db = self.db(path)?;
-        db.delete(k)?;
+    /// Level-check and write every operation of `batch` in one atomic write,
+    /// even across sub-dbs. Nothing is written if any operation is refused.
+    ///
+    /// Only available in column-family mode.
+    pub fn write_multi_path(&self, batch: MultiPathBatch) -> DbResult<()> {
+        let cfs = match &self.cfs {
+            Some(cfs) => cfs,
+            None => {
+                return Err(DbWrapError::Unsupported(
+                    "atomic writes across sub-dbs need column-family mode".to_string(),
+                ))
+            }
+        };
+        let mut paths: Vec<&str> = batch.ops.iter().map(|op| op.path()).collect();
+        paths.sort_unstable();
+        paths.dedup();
+        for path in &paths {
+            SubDbName::new(path)?;
+            self.check_writable(path)?;
+        }
+        let db = self.cf_db(cfs)?;
+        let _guard = self.locks.lock_entries(batch.ops.iter().map(|op| (op.path(), op.key())))?;
+        // Missing column families are only created once the whole batch is
+        // admitted, so a refused batch leaves nothing behind.
+        let mut handles = HashMap::new();
+        for path in paths {
+            handles.insert(path, (db.cf_handle(path), self.write_policy(path)));
+        }
+
+        let mut pending: HashMap<(&str, &[u8]), Option<Vec<u8>>> = HashMap::new();
+        let mut staged = Vec::with_capacity(batch.len());
+        for op in &batch.ops {
+            let (path, key) = (op.path(), op.key());
+            let (cf, policy) = &handles[path];
+            let old = match (pending.get(&(path, key)), cf) {
+                (Some(value), _) => value.clone(),
+                (None, Some(cf)) => db.get_cf(cf, key)?,
+                (None, None) => None,
+            };
+            let (new, lv, force) = match op {
+                MultiPathOp::Put { value, lv, force, .. } => (Some(value.as_slice()), *lv, *force),
+                MultiPathOp::Delete { lv, force, .. } => (None, *lv, *force),
+            };
+            let value = match admitted_value(&**policy, path, key, old.as_deref(), new, lv, force)? {
+                Ok(value) => value,
+                Err(refused) => return ensure_written(refused, key, lv),
+            };
+            pending.insert((path, key), value.clone());
+            staged.push((path, key, value));
+        }
+
+        for (path, _, value) in &staged {
+            if value.is_some() && handles[path].0.is_none() {
+                cfs.ensure_cf(&db, path, self.options_for(path))?;
+                handles.get_mut(path).unwrap().0 = Some(column_family(&db, path)?);
+            }
+        }
+        let mut write = WriteBatch::default();
+        for (path, key, value) in staged {
+            // Column families still missing only had deletes, there's nothing to delete.
+            let cf = match &handles[path].0 {
+                Some(cf) => cf,
+                None => continue,
+            };
+            match &value {
+                Some(value) => write.put_cf(cf, key, value),
+                None => write.delete_cf(cf, key),
+            }
+        }
+        db.write(write)?;
         Ok(())
     }
 
-    pub fn delete_batch<K: AsRef<[u8]>>(&self, keys: Vec<K>, path: &str) -> Result<()> {
-        let db = self.db(path)?;
-        let mut batch = WriteBatch::default();
+    /// Like `put_batch`, but atomically writes every admitted pair instead of
+    /// failing on the first refused one, and reports the outcome of each key
+    /// in order.
+    pub fn put_batch_partial<K: AsRef<[u8]>>(
+        &self,
+        pairs: Vec<(K, Vec<u8>)>,
+        lv: u8,
+        force: bool,
+        path: &str,
+    ) -> DbResult<Vec<(Vec<u8>, WriteOutcome)>> {
+        let db = self.writable_db(path)?;
+        let policy = self.write_policy(path);
+        let _guard = self.locks.lock(path, pairs.iter().map(|(k, _)| k.as_ref()))?;
+        let mut staged = Staged::new(&*policy, path, &db)?;
+        let mut report = Vec::with_capacity(pairs.len());
+        for (k, v) in pairs {
+            let outcome = staged
+                .write(k.as_ref(), Some(v), lv, force)
+                .unwrap_or_else(|e| WriteOutcome::Failed { error: e.to_string() });
+            report.push((k.as_ref().to_vec(), outcome));
+        }
+        db.write(staged.batch)?;
+        Ok(report)
+    }
+
+    /// Write `new` to `k` at level `lv` if its current payload is `expected`,
+    /// `None` meaning absent. Returns whether the value was swapped.
+    ///
+    /// The write is level-checked like `put` without force.
+    pub fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        k: K,
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+        lv: u8,
+        path: &str,
+    ) -> DbResult<bool> {
+        self.write_if(k.as_ref(), expected, Some(new), lv, path)
+    }
+
+    /// Write `v` to `k` at level `lv` unless `k` exists, returning whether it
+    /// was written.
+    pub fn put_if_absent<K: AsRef<[u8]>>(&self, k: K, v: Vec<u8>, lv: u8, path: &str) -> DbResult<bool> {
+        self.write_if(k.as_ref(), None, Some(v), lv, path)
+    }
+
+    /// Delete `k` if its current payload is `expected`, returning whether it
+    /// was deleted. The delete is level-checked at level `lv` without force.
+    pub fn delete_if_equals<K: AsRef<[u8]>>(&self, k: K, expected: &[u8], lv: u8, path: &str) -> DbResult<bool> {
+        self.write_if(k.as_ref(), Some(expected), None, lv, path)
+    }
+
+    fn write_if(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+        lv: u8,
+        path: &str,
+    ) -> DbResult<bool> {
+        let db = self.writable_db(path)?;
+        let policy = self.write_policy(path);
+        let _guard = self.locks.lock(path, Some(key))?;
+        let mut staged = Staged::new(&*policy, path, &db)?;
+        if staged.current_payload(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        let outcome = staged.write(key, new, lv, false)?;
+        ensure_written(outcome, key, lv)?;
+        db.write(staged.batch)?;
+        Ok(true)
+    }
+
+    /// Atomically replace the payload of `k` with `f(current)` at level `lv`,
+    /// deleting the key if `f` returns `None`. Returns the stored payload.
+    ///
+    /// The write is level-checked like `put` without force. It fails with
+    /// `WriteConflict` if the key stays locked by other writers for longer
+    /// than the lock timeout (5 s by default, see `with_lock_timeout`).
+    ///
+    /// `f` runs while the key is locked, so it must not call back into this
+    /// `DbWrap`: its writes could wait on that lock and fail the same way.
+    pub fn update<K, F>(&self, k: K, path: &str, lv: u8, mut f: F) -> DbResult<Option<Vec<u8>>>
+    where
+        K: AsRef<[u8]>,
+        F: FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let key = k.as_ref();
+        let db = self.writable_db(path)?;
+        let policy = self.write_policy(path);
+        let _guard = self.locks.lock(path, Some(key))?;
+        let mut staged = Staged::new(&*policy, path, &db)?;
+        let new = f(staged.current_payload(key)?.as_deref());
+        let outcome = staged.write(key, new, lv, false)?;
+        ensure_written(outcome, key, lv)?;
+        // The policy may have merged `new` with the stored value.
+        let stored = staged.current_payload(key)?;
+        db.write(staged.batch)?;
+        Ok(stored)
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&self, k: K, path: &str) -> DbResult<()> {
+        self.writable_db(path)?.delete(k.as_ref())
+    }
+
+    pub fn delete_batch<K: AsRef<[u8]>>(&self, keys: Vec<K>, path: &str) -> DbResult<()> {
+        let db = self.writable_db(path)?;
+        let mut batch = db.batch()?;
         for key in &keys {
             batch.delete(key);
         }
-        db.write(batch).map_err(|e| anyhow!("{:?}", e))?;
+        db.write(batch)?;
         Ok(())
     }
 
-    pub fn get_prefix<K: AsRef<[u8]>>(&self, k: K, path: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
-        let db = self.db(path)?;
-        let keys = self.search_keys_by_prefix(&k, &db);
+    /// Delete `k` if the write policy admits it at level `lv`.
+    ///
+    /// Returns `false` if the delete was refused.
+    pub fn delete_with_level<K: AsRef<[u8]>>(&self, k: K, lv: u8, force: bool, path: &str) -> DbResult<bool> {
+        let refused = self.delete_batch_with_level(vec![k], lv, force, path)?;
+        Ok(refused.is_empty())
+    }
+
+    /// Atomically delete every key of `keys` the write policy admits at level
+    /// `lv`, returning the refused ones.
+    pub fn delete_batch_with_level<K: AsRef<[u8]>>(
+        &self,
+        keys: Vec<K>,
+        lv: u8,
+        force: bool,
+        path: &str,
+    ) -> DbResult<Vec<Vec<u8>>> {
+        let db = self.writable_db(path)?;
+        let policy = self.write_policy(path);
+        let _guard = self.locks.lock(path, keys.iter().map(|k| k.as_ref()))?;
+        delete_admitted(&*policy, path, &db, &keys, lv, force)
+    }
+
+    pub fn get_prefix<K: AsRef<[u8]>>(&self, k: K, path: &str) -> DbResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let datas = self.get_prefix_with_level(k, LevelFilter::default(), path)?;
+        Ok(datas.into_iter().map(|(key, value, _)| (key, value)).collect())
+    }
+
+    /// Like `get_prefix`, but also returns each value's `DataLevel` and only
+    /// keeps values whose level passes `filter`.
+    pub fn get_prefix_with_level<K: AsRef<[u8]>>(
+        &self,
+        k: K,
+        filter: LevelFilter,
+        path: &str,
+    ) -> DbResult<Vec<(Vec<u8>, Vec<u8>, u8)>> {
         let mut datas = vec![];
-        if !keys.is_empty() {
-            for key in keys {
-                match db.get(&key) {
-                    Ok(Some(data)) => datas.push((key.clone(), data.data_without_lv())),
-                    Ok(None) => (),
-                    Err(e) => bail!("database get key error: {e:?}"),
-                }
+        for entry in self.iter_prefix(k, IterOptions::default(), path)? {
+            let entry = entry?;
+            if filter.contains(entry.2) {
+                datas.push(entry);
             }
         }
         Ok(datas)
     }
 
-    pub fn delete_prefix<K: AsRef<[u8]>>(&self, k: K, path: &str) -> Result<()> {
-        let db = self.db(path)?;
-        let keys = self.search_keys_by_prefix(&k, &db);
+    /// Read at most `limit` entries under prefix `k`, starting after the
+    /// position encoded in `cursor` (from the start if `None`).
+    ///
+    /// Fails with `DbWrapError::InvalidArgument` if `limit` is 0.
+    pub fn get_prefix_page<K: AsRef<[u8]>>(
+        &self,
+        k: K,
+        cursor: Option<&str>,
+        limit: usize,
+        path: &str,
+    ) -> DbResult<PrefixPage> {
+        if limit == 0 {
+            return Err(DbWrapError::InvalidArgument("page limit must be at least 1".to_string()));
+        }
+        let prefix = k.as_ref();
+        let mut lower = match cursor {
+            Some(cursor) => {
+                let key = hex::decode(cursor).map_err(|e| DbWrapError::InvalidCursor(e.to_string()))?;
+                if !is_prefix(prefix, &key) {
+                    return Err(DbWrapError::InvalidCursor("cursor is outside of the prefix".to_string()));
+                }
+                key
+            }
+            None => prefix.to_vec(),
+        };
+        if cursor.is_some() {
+            lower.push(0);
+        }
+        let db = self.sub_db(path)?;
+        // Read one entry past the page to know whether another page follows.
+        let opts = IterOptions::default().limit(limit.saturating_add(1));
+        let mut items = vec![];
+        for entry in DbIter::new(db, lower, prefix_successor(prefix), opts) {
+            let (key, value, _) = entry?;
+            items.push((key, value));
+        }
+        let next = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|(key, _)| hex::encode(key))
+        } else {
+            None
+        };
+        Ok(PrefixPage { items, next })
+    }
+
+    /// Lazily iterate the decoded entries whose key starts with `prefix`.
+    pub fn iter_prefix<K: AsRef<[u8]>>(&self, prefix: K, opts: IterOptions, path: &str) -> DbResult<DbIter> {
+        let db = self.sub_db(path)?;
+        let prefix = prefix.as_ref();
+        Ok(DbIter::new(db, prefix.to_vec(), prefix_successor(prefix), opts))
+    }
+
+    /// Lazily iterate the decoded entries with `start <= key < end`.
+    pub fn iter_range<K: AsRef<[u8]>>(&self, start: K, end: K, opts: IterOptions, path: &str) -> DbResult<DbIter> {
+        let db = self.sub_db(path)?;
+        Ok(DbIter::new(db, start.as_ref().to_vec(), Some(end.as_ref().to_vec()), opts))
+    }
+
+    /// Consistent point-in-time view of the sub-db at `path`.
+    pub fn snapshot(&self, path: &str) -> DbResult<DbSnapshot> {
+        Ok(DbSnapshot::new(SubSnapshot::new(self.sub_db(path)?)))
+    }
 
-        if !keys.is_empty() {
-            for key in keys {
-                db.delete(&key)?;
+    /// Atomically delete every key starting with `k`, without level checks,
+    /// returning how many were removed.
+    ///
+    /// The whole sub-db is locked from the count to the delete, so the count
+    /// is exact unless keys are written through a raw `db` handle meanwhile.
+    pub fn delete_prefix<K: AsRef<[u8]>>(&self, k: K, path: &str) -> DbResult<usize> {
+        let db = self.writable_db(path)?;
+        let _guard = self.locks.lock_path(path)?;
+        let prefix = k.as_ref();
+        let end = prefix_successor(prefix);
+        let mut batch = db.batch()?;
+        let mut count = 0;
+        let mut iter = db.raw_iter(range_read_opts(prefix, end.clone()))?;
+        iter.seek(prefix);
+        while let Some(key) = iter.key() {
+            // No finite upper bound for the range, delete key by key.
+            if end.is_none() {
+                batch.delete(key);
             }
+            count += 1;
+            iter.next();
         }
-        Ok(())
+        iter.status()?;
+        if count == 0 {
+            return Ok(0);
+        }
+        if let Some(end) = &end {
+            batch.delete_range(prefix, end);
+        }
+        db.write(batch)?;
+        Ok(count)
     }
 
-    fn search_keys_by_prefix<K: AsRef<[u8]>>(&self, prefix: K, db: &Arc<DB>) -> Vec<Vec<u8>> {
-        let mut keys = Vec::new();
-        let mut prev_iter = db.raw_iterator();
-        prev_iter.seek(&prefix);
-        while prev_iter.valid() {
-            match prev_iter.key() {
-                Some(key_slice) => {
-                    if is_prefix(prefix.as_ref(), key_slice) {
-                        keys.push(key_slice.to_vec());
+    /// Keys `delete_prefix` would remove, without deleting anything.
+    pub fn delete_prefix_dry_run<K: AsRef<[u8]>>(&self, k: K, path: &str) -> DbResult<Vec<Vec<u8>>> {
+        let db = self.sub_db(path)?;
+        self.search_keys_by_prefix(k, &db)
+    }
+
+    /// Atomically move every value under prefix `k` to level `to`, optionally
+    /// only those currently at level `from`. Returns the number of rewritten keys.
+    pub fn relevel_prefix<K: AsRef<[u8]>>(&self, k: K, from: Option<u8>, to: u8, path: &str) -> DbResult<usize> {
+        let db = self.writable_db(path)?;
+        // Scan under the sub-db lock so keys written meanwhile aren't missed.
+        let _guard = self.locks.lock_path(path)?;
+        let keys = self.search_keys_by_prefix(&k, &db)?;
+        let mut batch = db.batch()?;
+        for key in &keys {
+            let value = match db.get(key)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let (payload, lv) = split_level(&value)?;
+            if lv != to && from.map_or(true, |from| lv == from) {
+                batch.put(key, encode_value(payload, to));
+            }
+        }
+        let count = batch.len();
+        db.write(batch)?;
+        Ok(count)
+    }
+
+    /// Histogram of the values under prefix `k` (the whole sub-db for an empty
+    /// prefix) by `DataLevel`.
+    pub fn level_stats<K: AsRef<[u8]>>(&self, k: K, path: &str) -> DbResult<BTreeMap<u8, LevelStats>> {
+        let db = self.sub_db(path)?;
+        let mut stats = BTreeMap::<u8, LevelStats>::new();
+        for_each_prefix(&db, k.as_ref(), |_, value| {
+            let (payload, lv) = split_level(value)?;
+            let stat = stats.entry(lv).or_default();
+            stat.count += 1;
+            stat.bytes += payload.len() as u64;
+            Ok(())
+        })?;
+        Ok(stats)
+    }
+
+    /// Rewrite every value of the sub-db at `path` that still uses the legacy
+    /// `\n lv \n` framing (or no framing at all) with a current header.
+    ///
+    /// Values whose framing can't be decided are left untouched and reported.
+    ///
+    /// Reads and level checks decode legacy values too, so the sub-db keeps
+    /// serving while this runs. Each batch of keys is re-read and rewritten
+    /// under the sub-db lock, so concurrent level-checked writes aren't lost.
+    pub fn migrate_legacy_values(&self, path: &str) -> DbResult<MigrationReport> {
+        let db = self.writable_db(path)?;
+        let mut report = MigrationReport::default();
+        let mut lower = vec![];
+        loop {
+            let keys = {
+                let mut keys = Vec::with_capacity(MIGRATION_BATCH);
+                let mut iter = db.raw_iter(range_read_opts(&lower, None))?;
+                iter.seek(&lower);
+                while let Some(key) = iter.key() {
+                    if keys.len() == MIGRATION_BATCH {
+                        break;
+                    }
+                    keys.push(key.to_vec());
+                    iter.next();
+                }
+                iter.status()?;
+                keys
+            };
+            lower = match keys.last() {
+                Some(last) => [last.as_slice(), &[0]].concat(),
+                None => return Ok(report),
+            };
+
+            let _guard = self.locks.lock_path(path)?;
+            let mut batch = db.batch()?;
+            for key in &keys {
+                let value = match db.get(key)? {
+                    Some(value) => value,
+                    None => continue,
+                };
+                match detect_framing(&value) {
+                    Framing::Current(..) => report.current += 1,
+                    Framing::Legacy(lv, payload) => match detect_framing(payload) {
+                        Framing::Raw(_) => {
+                            batch.put(key, encode_value(payload, lv));
+                            report.migrated += 1;
+                        }
+                        _ => report.ambiguous.push(key.to_vec()),
+                    },
+                    Framing::Raw(payload) => {
+                        batch.put(key, encode_value(payload, DEFAULT_LEVEL));
+                        report.wrapped += 1;
                     }
+                    Framing::Invalid => report.ambiguous.push(key.to_vec()),
                 }
-                None => (),
             }
-            prev_iter.next();
+            if !batch.is_empty() {
+                db.write(batch)?;
+            }
         }
-        keys
     }
+
+    /// Delete every key starting with `k` the write policy admits at level
+    /// `lv`, returning the refused ones.
+    pub fn delete_prefix_with_level<K: AsRef<[u8]>>(
+        &self,
+        k: K,
+        lv: u8,
+        force: bool,
+        path: &str,
+    ) -> DbResult<Vec<Vec<u8>>> {
+        let db = self.writable_db(path)?;
+        let policy = self.write_policy(path);
+        // Scan under the sub-db lock so keys written meanwhile aren't missed.
+        let _guard = self.locks.lock_path(path)?;
+        let keys = self.search_keys_by_prefix(&k, &db)?;
+        delete_admitted(&*policy, path, &db, &keys, lv, force)
+    }
+
+    fn search_keys_by_prefix<K: AsRef<[u8]>>(&self, prefix: K, db: &SubDb) -> DbResult<Vec<Vec<u8>>> {
+        let prefix = prefix.as_ref();
+        self.search_keys(prefix, prefix_successor(prefix), db)
+    }
+
+    /// Keys with `lower <= key < upper`.
+    fn search_keys(&self, lower: &[u8], upper: Option<Vec<u8>>, db: &SubDb) -> DbResult<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        let mut iter = db.raw_iter(range_read_opts(lower, upper))?;
+        iter.seek(lower);
+        while let Some(key) = iter.key() {
+            keys.push(key.to_vec());
+            iter.next();
+        }
+        iter.status()?;
+        Ok(keys)
+    }
+}
+
+fn create_parent_dir(path: &str) -> DbResult<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+/// Collect the sub-db paths below `dir`, relative to `root`. A directory with
+/// a RocksDB `CURRENT` file is a sub-db, and is searched further for sub-dbs
+/// nested in it.
+fn find_sub_dbs(root: &Path, dir: &Path, paths: &mut Vec<String>) -> DbResult<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let sub = entry.path();
+        if sub.join("CURRENT").is_file() {
+            if let Ok(rel) = sub.strip_prefix(root) {
+                let name: Vec<_> = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+                paths.push(name.join("/"));
+            }
+        }
+        find_sub_dbs(root, &sub, paths)?;
+    }
+    Ok(())
+}
+
+/// Call `f` with the raw key and stored value of every entry starting with
+/// `prefix`, in key order.
+fn for_each_prefix<F>(db: &SubDb, prefix: &[u8], mut f: F) -> DbResult<()>
+where
+    F: FnMut(&[u8], &[u8]) -> DbResult<()>,
+{
+    let mut iter = db.raw_iter(prefix_read_opts(prefix))?;
+    iter.seek(prefix);
+    while let Some((key, value)) = iter.item() {
+        f(key, value)?;
+        iter.next();
+    }
+    iter.status()
+}
+
+/// Read options bounding iteration to the keys starting with `prefix`.
+fn prefix_read_opts(prefix: &[u8]) -> ReadOptions {
+    range_read_opts(prefix, prefix_successor(prefix))
+}
+
+/// Read options bounding iteration to `lower <= key < upper`.
+fn range_read_opts(lower: &[u8], upper: Option<Vec<u8>>) -> ReadOptions {
+    let mut opts = ReadOptions::default();
+    opts.set_iterate_lower_bound(lower.to_vec());
+    if let Some(upper) = upper {
+        opts.set_iterate_upper_bound(upper);
+    }
+    opts
+}
+
+/// Stored value of `key`, as it will be once the writes queued in `pending`
+/// are applied.
+fn current_value(db: &SubDb, pending: &HashMap<Vec<u8>, Option<Vec<u8>>>, key: &[u8]) -> DbResult<Option<Vec<u8>>> {
+    match pending.get(key) {
+        Some(value) => Ok(value.clone()),
+        None => Ok(db.get(key)?),
+    }
+}
+
+/// Writes of a `DbBatch` staged under its key locks.
+struct Staged<'a> {
+    policy: &'a dyn WritePolicy,
+    path: &'a str,
+    db: &'a SubDb,
+    pending: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    batch: SubBatch<'a>,
+}
+
+impl<'a> Staged<'a> {
+    fn new(policy: &'a dyn WritePolicy, path: &'a str, db: &'a SubDb) -> DbResult<Self> {
+        Ok(Staged {
+            policy,
+            path,
+            db,
+            pending: HashMap::new(),
+            batch: db.batch()?,
+        })
+    }
+
+    fn current(&self, key: &[u8]) -> DbResult<Option<Vec<u8>>> {
+        current_value(self.db, &self.pending, key)
+    }
+
+    fn current_payload(&self, key: &[u8]) -> DbResult<Option<Vec<u8>>> {
+        match self.current(key)? {
+            Some(value) => Ok(Some(split_level(&value)?.0.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    /// Level-check writing `new` (or deleting if `None`) to `key` at level
+    /// `lv` and stage it if admitted.
+    fn write(&mut self, key: &[u8], new: Option<Vec<u8>>, lv: u8, force: bool) -> DbResult<WriteOutcome> {
+        let old = self.current(key)?;
+        let value = match admitted_value(self.policy, self.path, key, old.as_deref(), new.as_deref(), lv, force)? {
+            Ok(value) => value,
+            Err(refused) => return Ok(refused),
+        };
+        match &value {
+            Some(value) => self.batch.put(key, value),
+            None => self.batch.delete(key),
+        }
+        self.pending.insert(key.to_vec(), value);
+        Ok(WriteOutcome::Written)
+    }
+}
+
+/// Atomically delete every key of `keys` the write policy admits at level
+/// `lv`, returning the refused ones. The caller holds the locks.
+fn delete_admitted<K: AsRef<[u8]>>(
+    policy: &dyn WritePolicy,
+    path: &str,
+    db: &SubDb,
+    keys: &[K],
+    lv: u8,
+    force: bool,
+) -> DbResult<Vec<Vec<u8>>> {
+    let mut staged = Staged::new(policy, path, db)?;
+    let mut refused = vec![];
+    for key in keys {
+        if staged.write(key.as_ref(), None, lv, force)? != WriteOutcome::Written {
+            refused.push(key.as_ref().to_vec());
+        }
+    }
+    db.write(staged.batch)?;
+    Ok(refused)
+}
+
+/// Encoded value to store for a write of `new` (or a delete if `None`) over
+/// the stored value `old`, or the outcome refusing it.
+fn admitted_value(
+    policy: &dyn WritePolicy,
+    path: &str,
+    key: &[u8],
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    lv: u8,
+    force: bool,
+) -> DbResult<Result<Option<Vec<u8>>, WriteOutcome>> {
+    Ok(match admit_write(policy, path, key, old, new, lv, force)? {
+        Admission::Accept => Ok(new.map(|v| encode_value(v, lv))),
+        Admission::Merge(merged) => Ok(Some(encode_value(&merged, lv))),
+        Admission::LevelConflict { existing } => Err(WriteOutcome::LevelConflict { existing }),
+        Admission::Reject(reason) => Err(WriteOutcome::Rejected { reason }),
+    })
+}
+
+/// Turn the outcome of a single-key `Staged::write` into an error unless it
+/// was written.
+fn ensure_written(outcome: WriteOutcome, key: &[u8], lv: u8) -> DbResult<()> {
+    match outcome {
+        WriteOutcome::Written => Ok(()),
+        WriteOutcome::LevelConflict { existing } => Err(DbWrapError::LevelConflict {
+            key: key.to_vec(),
+            existing,
+            requested: lv,
+        }),
+        WriteOutcome::Rejected { reason } => Err(DbWrapError::Rejected {
+            key: key.to_vec(),
+            reason,
+        }),
+        outcome => unreachable!("single-key write outcome {:?}", outcome),
+    }
+}
+
+/// Split a stored value into its payload and `DataLevel`.
+pub(crate) fn split_level(data: &[u8]) -> DbResult<(&[u8], u8)> {
+    if let (Some(header), payload) = decode_value(data)? {
+        return Ok((payload, header.level));
+    }
+    match detect_framing(data) {
+        // Not migrated yet, see `DbWrap::migrate_legacy_values`.
+        Framing::Legacy(lv, payload) => Ok((payload, lv)),
+        _ => Ok((data, DEFAULT_LEVEL)),
+    }
+}
+
+/// Payloads of the stored `values`, dropping their headers.
+pub(crate) fn strip_levels(values: Vec<Option<Vec<u8>>>) -> DbResult<Vec<Option<Vec<u8>>>> {
+    values
+        .into_iter()
+        .map(|v| match v {
+            Some(v) => Ok(Some(split_level(&v)?.0.to_vec())),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+/// Run `policy` against a pending write of `new` (or a delete if `None`) over
+/// the stored value `old`.
+fn admit_write(
+    policy: &dyn WritePolicy,
+    path: &str,
+    key: &[u8],
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    lv: u8,
+    force: bool,
+) -> DbResult<Admission> {
+    let old = match old {
+        Some(old) => Some(split_level(old)?),
+        None => None,
+    };
+    let ctx = WriteContext {
+        path,
+        key,
+        old,
+        new,
+        level: lv,
+        force,
+    };
+    Ok(policy.admit(&ctx))
 }
 
 pub fn is_prefix(prefix: &[u8], key: &[u8]) -> bool {
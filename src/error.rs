@@ -0,0 +1,122 @@
+use std::fmt;
+
+pub type DbResult<T> = std::result::Result<T, DbWrapError>;
+
+#[derive(Debug)]
+pub enum DbWrapError {
+    /// The key exists with a level the requested write can't override.
+    LevelConflict {
+        key: Vec<u8>,
+        existing: u8,
+        requested: u8,
+    },
+    /// The write policy refused the write.
+    Rejected { key: Vec<u8>, reason: String },
+    /// Another writer held the keys for too long; the write can be retried.
+    WriteConflict { path: String },
+    /// A sub-db could not be opened.
+    OpenFailed { path: String, source: rocksdb::Error },
+    /// The sub-db is still borrowed and can't be closed.
+    InUse(String),
+    /// The sub-db name is not acceptable.
+    InvalidPath(String),
+    /// A pagination cursor is malformed or doesn't belong to the prefix.
+    InvalidCursor(String),
+    /// A request argument is out of range.
+    InvalidArgument(String),
+    /// A stored value could not be decoded.
+    Codec(String),
+    /// The sub-db is opened read-only or as a secondary.
+    ReadOnly(String),
+    /// A configuration value is out of range.
+    InvalidConfig(String),
+    /// The operation isn't available in the current storage mode.
+    Unsupported(String),
+    Io(std::io::Error),
+    Rocks(rocksdb::Error),
+}
+
+impl DbWrapError {
+    /// Stable, machine-readable code of the error kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DbWrapError::LevelConflict { .. } => "LEVEL_CONFLICT",
+            DbWrapError::Rejected { .. } => "REJECTED",
+            DbWrapError::WriteConflict { .. } => "WRITE_CONFLICT",
+            DbWrapError::OpenFailed { .. } => "OPEN_FAILED",
+            DbWrapError::InUse(_) => "IN_USE",
+            DbWrapError::InvalidPath(_) => "INVALID_PATH",
+            DbWrapError::InvalidCursor(_) => "INVALID_CURSOR",
+            DbWrapError::InvalidArgument(_) => "INVALID_ARGUMENT",
+            DbWrapError::Codec(_) => "CODEC",
+            DbWrapError::ReadOnly(_) => "READ_ONLY",
+            DbWrapError::InvalidConfig(_) => "INVALID_CONFIG",
+            DbWrapError::Unsupported(_) => "UNSUPPORTED",
+            DbWrapError::Io(_) => "IO",
+            DbWrapError::Rocks(_) => "ROCKSDB",
+        }
+    }
+
+    /// Whether retrying the same operation may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, DbWrapError::WriteConflict { .. })
+    }
+}
+
+impl fmt::Display for DbWrapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbWrapError::LevelConflict {
+                key,
+                existing,
+                requested,
+            } => write!(
+                f,
+                "can't write data with level {} to key {} which exist with DataLevel {} without force",
+                requested,
+                hex::encode(key),
+                existing
+            ),
+            DbWrapError::Rejected { key, reason } => {
+                write!(f, "write to key {} rejected: {}", hex::encode(key), reason)
+            }
+            DbWrapError::WriteConflict { path } => {
+                write!(f, "write conflict on sub-db {}: keys are locked by another writer, retry", path)
+            }
+            DbWrapError::OpenFailed { path, source } => write!(f, "failed to open sub-db {}: {}", path, source),
+            DbWrapError::InUse(path) => write!(f, "sub-db {} is still in use", path),
+            DbWrapError::InvalidPath(reason) => write!(f, "invalid sub-db path: {}", reason),
+            DbWrapError::InvalidCursor(reason) => write!(f, "invalid cursor: {}", reason),
+            DbWrapError::InvalidArgument(reason) => write!(f, "invalid argument: {}", reason),
+            DbWrapError::Codec(reason) => write!(f, "value decode error: {}", reason),
+            DbWrapError::ReadOnly(path) => write!(f, "sub-db {} is opened read-only", path),
+            DbWrapError::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            DbWrapError::Unsupported(reason) => write!(f, "unsupported operation: {}", reason),
+            DbWrapError::Io(e) => write!(f, "io error: {}", e),
+            DbWrapError::Rocks(e) => write!(f, "rocksdb error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbWrapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbWrapError::OpenFailed { source, .. } => Some(source),
+            DbWrapError::Io(e) => Some(e),
+            DbWrapError::Rocks(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rocksdb::Error> for DbWrapError {
+    fn from(e: rocksdb::Error) -> Self {
+        DbWrapError::Rocks(e)
+    }
+}
+
+impl From<std::io::Error> for DbWrapError {
+    fn from(e: std::io::Error) -> Self {
+        DbWrapError::Io(e)
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error::{DbResult, DbWrapError};
+use serde::{Deserialize, Serialize};
+
+/// Magic bytes every value written by `DbWrap` starts with.
+pub const HEADER_MAGIC: [u8; 3] = [0xDB, b'W', b'R'];
+/// Current header format version.
+pub const HEADER_VERSION: u8 = 1;
+/// Encoded header length: magic + version + level + flags.
+pub const HEADER_LEN: usize = HEADER_MAGIC.len() + 3;
+/// Flag bits understood by this version, unknown bits make a header invalid.
+pub const HEADER_KNOWN_FLAGS: u8 = 0;
+
+const LEGACY_SEG: u8 = b'\n';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueHeader {
+    pub version: u8,
+    pub level: u8,
+    pub flags: u8,
+}
+
+impl ValueHeader {
+    pub fn new(level: u8) -> Self {
+        ValueHeader {
+            version: HEADER_VERSION,
+            level,
+            flags: 0,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let m = HEADER_MAGIC;
+        [m[0], m[1], m[2], self.version, self.level, self.flags]
+    }
+
+    /// Decode the header at the start of `data`.
+    ///
+    /// Returns `Ok(None)` if `data` does not start with the magic bytes, and an
+    /// error if it does but the header is truncated or of an unsupported version.
+    pub fn decode(data: &[u8]) -> DbResult<Option<ValueHeader>> {
+        if !data.starts_with(&HEADER_MAGIC) {
+            return Ok(None);
+        }
+        if data.len() < HEADER_LEN {
+            return Err(DbWrapError::Codec(format!("truncated value header: {} bytes", data.len())));
+        }
+        let m = HEADER_MAGIC.len();
+        let header = ValueHeader {
+            version: data[m],
+            level: data[m + 1],
+            flags: data[m + 2],
+        };
+        if header.version != HEADER_VERSION {
+            return Err(DbWrapError::Codec(format!(
+                "unsupported value header version {}",
+                header.version
+            )));
+        }
+        if header.flags & !HEADER_KNOWN_FLAGS != 0 {
+            return Err(DbWrapError::Codec(format!("unknown value header flags {:#04x}", header.flags)));
+        }
+        Ok(Some(header))
+    }
+}
+
+/// Prepend a current-version header with level `lv` to `value`.
+pub fn encode_value(value: &[u8], lv: u8) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LEN + value.len());
+    data.extend_from_slice(&ValueHeader::new(lv).encode());
+    data.extend_from_slice(value);
+    data
+}
+
+/// Split a stored value into its header and payload.
+///
+/// Values without a header are returned whole with `None`.
+pub fn decode_value(data: &[u8]) -> DbResult<(Option<ValueHeader>, &[u8])> {
+    match ValueHeader::decode(data)? {
+        Some(header) => Ok((Some(header), &data[HEADER_LEN..])),
+        None => Ok((None, data)),
+    }
+}
+
+/// How a stored value is framed, as seen by the legacy migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing<'a> {
+    /// Carries a valid current-version header.
+    Current(ValueHeader, &'a [u8]),
+    /// Framed with the pre-header `\n lv \n` prefix.
+    Legacy(u8, &'a [u8]),
+    /// No recognizable framing at all.
+    Raw(&'a [u8]),
+    /// Starts with the magic bytes but the header can't be decoded.
+    Invalid,
+}
+
+pub fn detect_framing(data: &[u8]) -> Framing<'_> {
+    match decode_value(data) {
+        Ok((Some(header), payload)) => Framing::Current(header, payload),
+        Err(_) => Framing::Invalid,
+        Ok((None, _)) => {
+            if data.len() >= 3 && data[0] == LEGACY_SEG && data[2] == LEGACY_SEG {
+                Framing::Legacy(data[1], &data[3..])
+            } else {
+                Framing::Raw(data)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationReport {
+    /// Legacy-framed values rewritten with a header.
+    pub migrated: usize,
+    /// Unframed values wrapped with a default-level header.
+    pub wrapped: usize,
+    /// Values already carrying a current header.
+    pub current: usize,
+    /// Keys left untouched because their framing could not be decided.
+    pub ambiguous: Vec<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = encode_value(b"payload", 2);
+        assert_eq!(
+            decode_value(&data).unwrap(),
+            (Some(ValueHeader::new(2)), &b"payload"[..])
+        );
+        assert_eq!(decode_value(&encode_value(b"", 0)).unwrap().1, b"");
+    }
+
+    #[test]
+    fn decode_without_magic() {
+        assert_eq!(ValueHeader::decode(b"").unwrap(), None);
+        assert_eq!(ValueHeader::decode(b"plain").unwrap(), None);
+        assert_eq!(ValueHeader::decode(&HEADER_MAGIC[..2]).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let header = ValueHeader::new(1).encode();
+        for len in HEADER_MAGIC.len()..HEADER_LEN {
+            assert!(matches!(ValueHeader::decode(&header[..len]), Err(DbWrapError::Codec(_))));
+        }
+    }
+
+    #[test]
+    fn decode_wrong_version() {
+        let mut header = ValueHeader::new(1).encode();
+        header[HEADER_MAGIC.len()] = HEADER_VERSION + 1;
+        assert!(matches!(ValueHeader::decode(&header), Err(DbWrapError::Codec(_))));
+    }
+
+    #[test]
+    fn decode_unknown_flags() {
+        let mut header = ValueHeader::new(1).encode();
+        header[HEADER_LEN - 1] = 0x80;
+        assert!(matches!(ValueHeader::decode(&header), Err(DbWrapError::Codec(_))));
+    }
+
+    #[test]
+    fn framing() {
+        let current = encode_value(b"v", 1);
+        assert_eq!(detect_framing(&current), Framing::Current(ValueHeader::new(1), b"v"));
+        assert_eq!(detect_framing(b"\n\x02\nv"), Framing::Legacy(2, b"v"));
+        assert_eq!(detect_framing(b"\n\x02\n"), Framing::Legacy(2, b""));
+        assert_eq!(detect_framing(b"\n\x02"), Framing::Raw(b"\n\x02"));
+        assert_eq!(detect_framing(b"v"), Framing::Raw(b"v"));
+        assert_eq!(detect_framing(&current[..HEADER_LEN - 1]), Framing::Invalid);
+    }
+
+    #[test]
+    fn framing_ambiguous() {
+        // A legacy value whose payload is itself framed: either a legacy
+        // frame around a framed payload or a double-framed value.
+        let mut data = b"\n\x01\n".to_vec();
+        data.extend_from_slice(&encode_value(b"v", 2));
+        match detect_framing(&data) {
+            Framing::Legacy(1, payload) => {
+                assert_eq!(detect_framing(payload), Framing::Current(ValueHeader::new(2), b"v"))
+            }
+            framing => panic!("unexpected framing {:?}", framing),
+        }
+        match detect_framing(b"\n\x01\n\n\x02\nv") {
+            Framing::Legacy(1, payload) => assert_eq!(detect_framing(payload), Framing::Legacy(2, b"v")),
+            framing => panic!("unexpected framing {:?}", framing),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::database::split_level;
+use crate::error::DbResult;
+use crate::subdb::{SubDb, SubSnapshot};
+use rocksdb::ReadOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Entries read from RocksDB per refill of a `DbIter`.
+const ITER_CHUNK: usize = 256;
+
+/// Decoded `(key, value, level)` entry yielded by a `DbIter`.
+pub type DbEntry = (Vec<u8>, Vec<u8>, u8);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IterOptions {
+    /// Walk keys in descending order.
+    pub reverse: bool,
+    /// Stop after this many entries.
+    pub limit: Option<usize>,
+}
+
+impl IterOptions {
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// One page of a prefix walk, see `DbWrap::get_prefix_page`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixPage {
+    pub items: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Opaque cursor to pass back for the next page, `None` on the last page.
+    pub next: Option<String>,
+}
+
+/// Lazy iterator over the entries of a sub-db within `[lower, upper)`.
+///
+/// Entries are read in small chunks bounded by RocksDB's iterate bounds, so
+/// the iterator never reads past the end of its range nor buffers the whole
+/// range in memory.
+pub struct DbIter {
+    db: SubDb,
+    snapshot: Option<Arc<SubSnapshot>>,
+    lower: Vec<u8>,
+    upper: Option<Vec<u8>>,
+    reverse: bool,
+    remaining: Option<usize>,
+    buf: VecDeque<DbResult<DbEntry>>,
+    done: bool,
+}
+
+impl DbIter {
+    pub(crate) fn new(db: SubDb, lower: Vec<u8>, upper: Option<Vec<u8>>, opts: IterOptions) -> Self {
+        DbIter {
+            db,
+            snapshot: None,
+            lower,
+            upper,
+            reverse: opts.reverse,
+            remaining: opts.limit,
+            buf: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Read every chunk from `snapshot` instead of the latest state.
+    pub(crate) fn at_snapshot(
+        snapshot: Arc<SubSnapshot>,
+        lower: Vec<u8>,
+        upper: Option<Vec<u8>>,
+        opts: IterOptions,
+    ) -> Self {
+        let mut iter = DbIter::new(snapshot.db().clone(), lower, upper, opts);
+        iter.snapshot = Some(snapshot);
+        iter
+    }
+
+    fn fill(&mut self) {
+        let want = self.remaining.map_or(ITER_CHUNK, |r| r.min(ITER_CHUNK));
+        if want == 0 {
+            self.done = true;
+            return;
+        }
+        let mut opts = match &self.snapshot {
+            Some(snapshot) => snapshot.read_opts(),
+            None => ReadOptions::default(),
+        };
+        opts.set_iterate_lower_bound(self.lower.clone());
+        if let Some(upper) = &self.upper {
+            opts.set_iterate_upper_bound(upper.clone());
+        }
+        let mut iter = match self.db.raw_iter(opts) {
+            Ok(iter) => iter,
+            Err(e) => {
+                self.buf.push_back(Err(e));
+                self.done = true;
+                return;
+            }
+        };
+        if self.reverse {
+            match &self.upper {
+                Some(upper) => {
+                    iter.seek_for_prev(upper);
+                    if iter.key() == Some(upper.as_slice()) {
+                        iter.prev();
+                    }
+                }
+                None => iter.seek_to_last(),
+            }
+        } else {
+            iter.seek(&self.lower);
+        }
+
+        let mut last = None;
+        let mut read = 0;
+        while read < want {
+            let (key, value) = match iter.item() {
+                Some(item) => item,
+                None => break,
+            };
+            self.buf
+                .push_back(split_level(value).map(|(payload, lv)| (key.to_vec(), payload.to_vec(), lv)));
+            last = Some(key.to_vec());
+            read += 1;
+            if self.reverse {
+                iter.prev();
+            } else {
+                iter.next();
+            }
+        }
+        if let Err(e) = iter.status() {
+            self.buf.push_back(Err(e));
+            self.done = true;
+            return;
+        }
+        if let Some(r) = self.remaining.as_mut() {
+            *r -= read;
+        }
+        match last {
+            // Narrow the range past the last key read, the next chunk resumes there.
+            Some(key) if read == want => {
+                if self.reverse {
+                    self.upper = Some(key);
+                } else {
+                    self.lower = key;
+                    self.lower.push(0);
+                }
+            }
+            _ => self.done = true,
+        }
+    }
+}
+
+impl Iterator for DbIter {
+    type Item = DbResult<DbEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() && !self.done {
+            self.fill();
+        }
+        self.buf.pop_front()
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, or `None` if
+/// there is none (empty prefix or all `0xff` bytes).
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut succ = prefix.to_vec();
+    while let Some(last) = succ.pop() {
+        if last != u8::MAX {
+            succ.push(last + 1);
+            return Some(succ);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn successor() {
+        assert_eq!(prefix_successor(b"a"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_successor(&[0x00]), Some(vec![0x01]));
+    }
+
+    #[test]
+    fn successor_carries_past_0xff() {
+        assert_eq!(prefix_successor(&[b'a', 0xff]), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(&[b'a', 0xff, 0xff]), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(&[0x01, 0xfe, 0xff]), Some(vec![0x01, 0xff]));
+    }
+
+    #[test]
+    fn successor_unbounded() {
+        assert_eq!(prefix_successor(b""), None);
+        assert_eq!(prefix_successor(&[0xff]), None);
+        assert_eq!(prefix_successor(&[0xff, 0xff, 0xff]), None);
+    }
+}
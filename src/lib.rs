@@ -1,10 +1,28 @@
 #![allow(clippy::all)]
 
+pub mod batch;
 pub mod config;
 pub mod database;
+pub mod error;
+pub mod header;
+pub mod iter;
+mod lock;
+pub mod name;
+pub mod policy;
+pub mod registry;
+pub mod snapshot;
+mod subdb;
 
+pub use batch::*;
 pub use config::*;
 pub use database::*;
+pub use error::*;
+pub use header::*;
+pub use iter::*;
+pub use name::*;
+pub use policy::*;
+pub use registry::*;
+pub use snapshot::*;
 #[cfg(feature = "server")]
 pub use db_server::*;
 pub use rocksdb;
@@ -44,14 +62,22 @@ pub fn debug_hash_data(data: &[u8]) -> String {
 
 #[cfg(feature = "server")]
 pub mod db_server {
-    use crate::config::RocksdbOptions;
-    use crate::database::DbWrap;
+    use crate::batch::DbBatch;
+    use crate::config::{OpenMode, OptionProfile, PathOptions, RocksdbOptions, SharedOptions};
+    use crate::database::{DbWrap, LevelFilter, MultiPathBatch};
+    use crate::error::{DbResult, DbWrapError};
+    use crate::name::SubDbName;
+    use crate::snapshot::SnapshotRead;
     use anyhow::{bail, Result};
     use rocket::{post, routes, State};
     use rocket_contrib::json::Json;
     use rocksdb::Options;
     use serde::{Deserialize, Serialize};
     use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Most entries returned by one `GetPrefixPage` request.
+    pub const MAX_PAGE_LIMIT: usize = 1000;
 
     #[derive(Deserialize, Clone)]
     pub struct DbConfig {
@@ -60,28 +86,127 @@ pub mod db_server {
         pub db_path: String,
         pub token: String,
         pub options: Option<RocksdbOptions>,
+        /// Profile applied to `options` for sub-dbs without an override.
+        pub profile: Option<OptionProfile>,
+        /// Per-path overrides, the first matching pattern wins.
+        pub path_options: Option<Vec<PathOptions>>,
+        /// Most sub-dbs kept open at once, unbounded if unset.
+        pub max_open_dbs: Option<usize>,
+        /// Store sub-dbs as column families of one RocksDB instance.
+        pub column_families: Option<bool>,
+        /// Serve sub-dbs read-only or as secondaries of another process.
+        pub open_mode: Option<OpenMode>,
+        /// How often a secondary catches up with its primary, in milliseconds.
+        pub catch_up_interval_ms: Option<u64>,
     }
 
     impl DbConfig {
-        pub fn get_opt(&self) -> Options {
-            if let Some(opt) = self.options.clone() {
-                opt.into()
+        pub fn get_opt(&self) -> DbResult<Options> {
+            let mut opt = if let Some(opt) = &self.options {
+                opt.to_options()?
             } else {
                 Options::default()
+            };
+            if let Some(profile) = self.profile {
+                profile.apply(&mut opt);
+            }
+            Ok(opt)
+        }
+
+        fn shared(&self) -> Option<&SharedOptions> {
+            self.options.as_ref().and_then(|opt| opt.shared.as_ref())
+        }
+
+        /// Check the base and per-path RocksDB options.
+        pub fn validate(&self) -> DbResult<()> {
+            if let Some(opt) = &self.options {
+                opt.validate()?;
+            }
+            let budget = self.shared().and_then(|shared| shared.write_buffer_budget);
+            if budget.is_some() && self.max_open_dbs.is_none() && !self.column_families.unwrap_or(false) {
+                return Err(DbWrapError::InvalidConfig(
+                    "shared.write_buffer_budget: needs max_open_dbs or column_families to bound memory".to_string(),
+                ));
+            }
+            for path_opt in self.path_options.iter().flatten() {
+                if let Some(opt) = &path_opt.options {
+                    opt.inherit_shared(self.shared()).and_then(|opt| opt.validate()).map_err(|e| match e {
+                        DbWrapError::InvalidConfig(reason) => {
+                            DbWrapError::InvalidConfig(format!("path_options {:?}: {}", path_opt.pattern, reason))
+                        }
+                        e => e,
+                    })?;
+                }
+            }
+            if matches!(self.open_mode, Some(OpenMode::Secondary { .. })) {
+                let base = self.options.iter().map(|opt| ("options".to_string(), opt));
+                let paths = self.path_options.iter().flatten().filter_map(|path_opt| {
+                    let opt = path_opt.options.as_ref()?;
+                    Some((format!("path_options {:?}", path_opt.pattern), opt))
+                });
+                for (at, opt) in base.chain(paths) {
+                    if opt.max_open_files.unwrap_or(-1) != -1 {
+                        return Err(DbWrapError::InvalidConfig(format!(
+                            "{}: max_open_files must be -1 (unlimited) in the secondary open_mode",
+                            at
+                        )));
+                    }
+                }
+            }
+            if self.catch_up_interval_ms.is_some() && !matches!(self.open_mode, Some(OpenMode::Secondary { .. })) {
+                return Err(DbWrapError::InvalidConfig(
+                    "catch_up_interval_ms: needs the secondary open_mode".to_string(),
+                ));
             }
+            Ok(())
         }
     }
 
     pub fn load_db_server_config() -> Result<DbConfig> {
         let content = std::fs::read_to_string("db_config.toml")?;
         match toml::from_str::<DbConfig>(&content) {
-            Ok(config) => Ok(config),
+            Ok(config) => {
+                if let Err(e) = config.validate() {
+                    bail!("failed to load config: {}", e);
+                }
+                Ok(config)
+            }
             Err(_) => bail!("failed to load config"),
         }
     }
 
-    fn to_string<V: Serialize>(value: V, at: &str) -> Result<String, String> {
-        serde_json::to_string(&value).map_err(|e| format!("{} serialize err: {:?}", at, e))
+    /// Error returned to HTTP clients, `code` is stable across releases.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct HttpError {
+        pub code: String,
+        pub message: String,
+    }
+
+    impl HttpError {
+        fn new(code: &str, message: String) -> Self {
+            HttpError {
+                code: code.to_string(),
+                message,
+            }
+        }
+    }
+
+    impl From<DbWrapError> for HttpError {
+        fn from(e: DbWrapError) -> Self {
+            HttpError::new(e.code(), e.to_string())
+        }
+    }
+
+    fn to_string<V: Serialize>(value: V, at: &str) -> Result<String, HttpError> {
+        serde_json::to_string(&value)
+            .map_err(|e| HttpError::new("SERIALIZE", format!("{} serialize err: {:?}", at, e)))
+    }
+
+    fn respond<V: Serialize>(res: DbResult<V>, at: &str) -> Result<String, HttpError> {
+        match res {
+            Ok(value) => to_string(&value, at),
+            Err(e) => Err(e.into()),
+        }
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -94,12 +219,43 @@ pub mod db_server {
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub enum RequestType {
         Get(Vec<u8>),
+        GetWithLevel(Vec<u8>),
+        MultiGet(Vec<Vec<u8>>),
         Put(Vec<u8>, Vec<u8>, u8, bool),
-        PutBatch(Vec<(Vec<u8>, Vec<u8>)>, u8, bool),
-        Delete(Vec<u8>),
-        DeleteBatch(Vec<Vec<u8>>),
+        /// Pairs, level, force and whether to write the admitted pairs only
+        /// and report each key's outcome instead of failing the whole batch.
+        PutBatch(Vec<(Vec<u8>, Vec<u8>)>, u8, bool, #[serde(default)] bool),
+        Batch(DbBatch),
+        Delete(Vec<u8>, u8, bool),
+        /// Key, expected payload (`None` for absent), new value and level.
+        CompareAndSwap(Vec<u8>, Option<Vec<u8>>, Vec<u8>, u8),
+        PutIfAbsent(Vec<u8>, Vec<u8>, u8),
+        /// Key, expected payload and level.
+        DeleteIfEquals(Vec<u8>, Vec<u8>, u8),
+        DeleteBatch(Vec<Vec<u8>>, u8, bool),
         GetPrefix(Vec<u8>),
-        DeletePrefix(Vec<u8>),
+        GetPrefixWithLevel(Vec<u8>, LevelFilter),
+        /// Prefix, cursor and limit, capped to `MAX_PAGE_LIMIT`.
+        GetPrefixPage(Vec<u8>, Option<String>, usize),
+        /// Level-checked, key by key, returning the refused keys.
+        DeletePrefix(Vec<u8>, u8, bool),
+        DeletePrefixDryRun(Vec<u8>),
+        RelevelPrefix(Vec<u8>, Option<u8>, u8),
+        LevelStats(Vec<u8>),
+        /// Rewrite legacy-framed values of sub-dbs created by older releases
+        /// with a header.
+        MigrateLegacy,
+        ListOpen,
+        ListOnDisk,
+        PoolMetrics,
+        Close,
+        Destroy,
+        Rename(String),
+        /// Atomic writes across sub-dbs, column-family mode only. Paths are
+        /// taken from the batch, not from the request.
+        WriteMultiPath(MultiPathBatch),
+        /// Reads all served from one snapshot of the sub-db.
+        SnapshotRead(Vec<SnapshotRead>),
     }
 
     #[post("/db_request", format = "json", data = "<request>")]
@@ -107,62 +263,125 @@ pub mod db_server {
         db_ref: State<Arc<DbWrap>>,
         token: State<Arc<String>>,
         request: Json<HttpRequest>,
-    ) -> Json<Result<String, String>> {
+    ) -> Json<Result<String, HttpError>> {
         if token.clone().as_str() != request.0.token.as_str() {
-            return Json(Err("Invalid token".to_string()));
+            return Json(Err(HttpError::new("INVALID_TOKEN", "Invalid token".to_string())));
         }
         let path = request.0.path.clone();
-        let res = match request.0.req {
-            RequestType::Get(key) => match db_ref.get(key, &path) {
-                Ok(res) => to_string(&res, "db_get"),
-                Err(e) => Err(format!("db_req failed for: {:?}", e)),
-            },
+        if !matches!(
+            request.0.req,
+            RequestType::ListOpen
+                | RequestType::ListOnDisk
+                | RequestType::PoolMetrics
+                | RequestType::WriteMultiPath(_)
+        ) {
+            if let Err(e) = SubDbName::new(&path) {
+                return Json(Err(e.into()));
+            }
+        }
+        let res = match request.0.req.clone() {
+            RequestType::Get(key) => respond(db_ref.get(key, &path), "db_get"),
+            RequestType::GetWithLevel(key) => respond(db_ref.get_with_level(key, &path), "db_get_with_level"),
+            RequestType::MultiGet(keys) => respond(db_ref.multi_get(keys, &path), "db_multi_get"),
             RequestType::Put(key, value, level, force) => {
-                match db_ref.put(key, value, level, force, &path) {
-                    Ok(res) => to_string(&res, "db_put"),
-                    Err(e) => Err(format!("db_req failed for: {:?}", e)),
-                }
+                respond(db_ref.put(key, value, level, force, &path), "db_put")
             }
-            RequestType::PutBatch(pairs, level, force) => {
-                match db_ref.put_batch(pairs, level, force, &path) {
-                    Ok(res) => to_string(&res, "db_put_batch"),
-                    Err(e) => Err(format!("db_req failed for: {:?}", e)),
-                }
+            RequestType::PutBatch(pairs, level, force, false) => {
+                respond(db_ref.put_batch(pairs, level, force, &path), "db_put_batch")
             }
-            RequestType::Delete(key) => match db_ref.delete(key, &path) {
-                Ok(res) => to_string(&res, "db_delete"),
-                Err(e) => Err(format!("db_req failed for: {:?}", e)),
-            },
-            RequestType::DeleteBatch(keys) => match db_ref.delete_batch(keys, &path) {
-                Ok(res) => to_string(&res, "db_delete_batch"),
-                Err(e) => Err(format!("db_req failed for: {:?}", e)),
-            },
-            RequestType::GetPrefix(key) => match db_ref.get_prefix(key, &path) {
-                Ok(res) => to_string(&res, "db_get_prefix"),
-                Err(e) => Err(format!("db_req failed for: {:?}", e)),
-            },
-            RequestType::DeletePrefix(key) => match db_ref.delete_prefix(key, &path) {
-                Ok(res) => to_string(&res, "db_delete_prefix"),
-                Err(e) => Err(format!("db_req failed for: {:?}", e)),
-            },
+            RequestType::PutBatch(pairs, level, force, true) => {
+                respond(db_ref.put_batch_partial(pairs, level, force, &path), "db_put_batch")
+            }
+            RequestType::Batch(batch) => respond(db_ref.write_batch(batch, &path), "db_write_batch"),
+            RequestType::Delete(key, level, force) => {
+                respond(db_ref.delete_with_level(key, level, force, &path), "db_delete")
+            }
+            RequestType::CompareAndSwap(key, expected, value, level) => respond(
+                db_ref.compare_and_swap(key, expected.as_deref(), value, level, &path),
+                "db_compare_and_swap",
+            ),
+            RequestType::PutIfAbsent(key, value, level) => {
+                respond(db_ref.put_if_absent(key, value, level, &path), "db_put_if_absent")
+            }
+            RequestType::DeleteIfEquals(key, expected, level) => respond(
+                db_ref.delete_if_equals(key, &expected, level, &path),
+                "db_delete_if_equals",
+            ),
+            RequestType::DeleteBatch(keys, level, force) => respond(
+                db_ref.delete_batch_with_level(keys, level, force, &path),
+                "db_delete_batch",
+            ),
+            RequestType::GetPrefix(key) => respond(db_ref.get_prefix(key, &path), "db_get_prefix"),
+            RequestType::GetPrefixWithLevel(key, filter) => respond(
+                db_ref.get_prefix_with_level(key, filter, &path),
+                "db_get_prefix_with_level",
+            ),
+            RequestType::GetPrefixPage(key, cursor, limit) => respond(
+                db_ref.get_prefix_page(key, cursor.as_deref(), limit.min(MAX_PAGE_LIMIT), &path),
+                "db_get_prefix_page",
+            ),
+            RequestType::DeletePrefix(key, level, force) => respond(
+                db_ref.delete_prefix_with_level(key, level, force, &path),
+                "db_delete_prefix",
+            ),
+            RequestType::DeletePrefixDryRun(key) => {
+                respond(db_ref.delete_prefix_dry_run(key, &path), "db_delete_prefix_dry_run")
+            }
+            RequestType::RelevelPrefix(key, from, to) => {
+                respond(db_ref.relevel_prefix(key, from, to, &path), "db_relevel_prefix")
+            }
+            RequestType::LevelStats(key) => respond(db_ref.level_stats(key, &path), "db_level_stats"),
+            RequestType::MigrateLegacy => respond(db_ref.migrate_legacy_values(&path), "db_migrate_legacy"),
+            RequestType::ListOpen => respond(Ok(db_ref.list_open()), "db_list_open"),
+            RequestType::ListOnDisk => respond(db_ref.list_on_disk(), "db_list_on_disk"),
+            RequestType::PoolMetrics => respond(Ok(db_ref.pool_metrics()), "db_pool_metrics"),
+            RequestType::Close => respond(db_ref.close(&path), "db_close"),
+            RequestType::Destroy => respond(db_ref.destroy(&path), "db_destroy"),
+            RequestType::Rename(to) => respond(db_ref.rename(&path, &to), "db_rename"),
+            RequestType::WriteMultiPath(batch) => respond(db_ref.write_multi_path(batch), "db_write_multi_path"),
+            RequestType::SnapshotRead(reads) => respond(
+                db_ref.snapshot(&path).and_then(|snapshot| snapshot.read_all(reads)),
+                "db_snapshot_read",
+            ),
         };
         log::debug!(target: "database_server", "Handle Request: {:?}, Response: {res:?}", request.0);
         Json(res)
     }
 
-    pub fn mount_db_server(db_config: DbConfig) {
-        let db = DbWrap::new(&db_config.db_path, db_config.get_opt());
+    /// Open the databases of `db_config` and serve them until the server
+    /// fails, returning why.
+    pub fn mount_db_server(db_config: DbConfig) -> Result<()> {
+        db_config.validate()?;
+        let opt = db_config.get_opt()?;
+        let mut db = DbWrap::new(&db_config.db_path, opt.clone());
+        for path_opt in db_config.path_options.iter().flatten() {
+            let path_opts = path_opt.to_options(&opt, db_config.shared())?;
+            db = db.with_path_options(&path_opt.pattern, path_opts);
+        }
+        if let Some(max) = db_config.max_open_dbs {
+            db = db.with_max_open_dbs(max);
+        }
+        if db_config.column_families.unwrap_or(false) {
+            db = db.with_column_families();
+        }
+        if let Some(mode) = db_config.open_mode.clone() {
+            db = db.with_open_mode(mode);
+        }
         let db_ref = Arc::new(db);
+        if let Some(interval) = db_config.catch_up_interval_ms {
+            DbWrap::spawn_catch_up(&db_ref, Duration::from_millis(interval));
+        }
         let token = Arc::new(db_config.token.clone());
         /////////////////////////////////////////////////////////////////
         let mut rocket_config = rocket::Config::production();
         rocket_config.set_port(db_config.port);
         rocket_config.set_workers(db_config.num_workers);
 
-        rocket::custom(rocket_config)
+        let err = rocket::custom(rocket_config)
             .mount("/", routes![db_request])
             .manage(db_ref)
             .manage(token)
             .launch();
+        bail!("failed to launch db server: {}", err)
     }
 }
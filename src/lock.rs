@@ -0,0 +1,153 @@
+use crate::error::{DbResult, DbWrapError};
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+const DEFAULT_STRIPES: usize = 1024;
+const PATH_STRIPES: usize = 64;
+/// Writes needing more key stripes of one sub-db lock the whole sub-db instead.
+const BULK_STRIPES: usize = 64;
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Striped per-key locks serializing read-check-write sequences on the same keys.
+///
+/// Every lock also takes a coarse per-sub-db stripe, shared for writes to a
+/// few keys and exclusive for bulk writes. A bulk write to one sub-db thus
+/// holds one coarse stripe instead of most key stripes, and only stalls
+/// writers of the sub-dbs sharing that stripe.
+pub(crate) struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+    paths: Vec<RwLock<()>>,
+    timeout: Duration,
+}
+
+pub(crate) struct KeyGuard<'a> {
+    _keys: Vec<MutexGuard<'a, ()>>,
+    _shared: Vec<RwLockReadGuard<'a, ()>>,
+    _exclusive: Vec<RwLockWriteGuard<'a, ()>>,
+}
+
+impl KeyLocks {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        KeyLocks {
+            stripes: (0..DEFAULT_STRIPES).map(|_| Mutex::new(())).collect(),
+            paths: (0..PATH_STRIPES).map(|_| RwLock::new(())).collect(),
+            timeout,
+        }
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn stripe(&self, path: &str, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        key.hash(&mut hasher);
+        (hasher.finish() % self.stripes.len() as u64) as usize
+    }
+
+    fn path_stripe(&self, path: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        (hasher.finish() % self.paths.len() as u64) as usize
+    }
+
+    /// Lock every key of `keys` in sub-db `path`, failing with a retryable
+    /// `DbWrapError::WriteConflict` if they can't all be acquired within the
+    /// timeout.
+    pub(crate) fn lock<'k, I>(&self, path: &str, keys: I) -> DbResult<KeyGuard<'_>>
+    where
+        I: IntoIterator<Item = &'k [u8]>,
+    {
+        self.lock_entries(keys.into_iter().map(|k| (path, k)))
+    }
+
+    /// Lock the whole sub-db `path`, as a bulk write does.
+    pub(crate) fn lock_path(&self, path: &str) -> DbResult<KeyGuard<'_>> {
+        let paths = Some((self.path_stripe(path), (true, path))).into_iter().collect();
+        self.acquire(paths, BTreeMap::new(), self.deadline())
+    }
+
+    /// Like `lock`, for `(path, key)` pairs spanning several sub-dbs.
+    pub(crate) fn lock_entries<'k, I>(&self, entries: I) -> DbResult<KeyGuard<'_>>
+    where
+        I: IntoIterator<Item = (&'k str, &'k [u8])>,
+    {
+        let (paths, stripes) = self.plan(entries);
+        self.acquire(paths, stripes, self.deadline())
+    }
+
+    /// When a lock taken now times out, `None` if that's past what `Instant`
+    /// can represent (e.g. for `Duration::MAX`).
+    fn deadline(&self) -> Option<Instant> {
+        Instant::now().checked_add(self.timeout)
+    }
+
+    /// Path stripes, and whether to take them exclusively, plus key stripes
+    /// needed to lock `entries`.
+    fn plan<'k, I>(&self, entries: I) -> (BTreeMap<usize, (bool, &'k str)>, BTreeMap<usize, &'k str>)
+    where
+        I: IntoIterator<Item = (&'k str, &'k [u8])>,
+    {
+        let mut keys = BTreeMap::<&str, BTreeSet<usize>>::new();
+        for (path, k) in entries {
+            keys.entry(path).or_default().insert(self.stripe(path, k));
+        }
+        let mut paths = BTreeMap::<usize, (bool, &str)>::new();
+        let mut stripes = BTreeMap::<usize, &str>::new();
+        for (path, key_stripes) in keys {
+            let bulk = key_stripes.len() > BULK_STRIPES;
+            let (exclusive, _) = paths.entry(self.path_stripe(path)).or_insert((false, path));
+            *exclusive |= bulk;
+            if !bulk {
+                for i in key_stripes {
+                    stripes.entry(i).or_insert(path);
+                }
+            }
+        }
+        (paths, stripes)
+    }
+
+    fn acquire<'a>(
+        &'a self,
+        paths: BTreeMap<usize, (bool, &str)>,
+        stripes: BTreeMap<usize, &str>,
+        deadline: Option<Instant>,
+    ) -> DbResult<KeyGuard<'a>> {
+        // Path stripes are taken before key stripes and both in ascending
+        // order, so that writers locking overlapping sets can't deadlock.
+        let conflict = |path: &str| DbWrapError::WriteConflict { path: path.to_string() };
+        let (mut shared, mut exclusive) = (vec![], vec![]);
+        for (i, (bulk, path)) in paths {
+            let (stripe, timed_out) = (&self.paths[i], || conflict(path));
+            match (bulk, deadline) {
+                (true, Some(deadline)) => exclusive.push(stripe.try_write_until(deadline).ok_or_else(timed_out)?),
+                (true, None) => exclusive.push(stripe.write()),
+                (false, Some(deadline)) => shared.push(stripe.try_read_until(deadline).ok_or_else(timed_out)?),
+                (false, None) => shared.push(stripe.read()),
+            }
+        }
+        let mut key_guards = Vec::with_capacity(stripes.len());
+        for (i, path) in stripes {
+            let stripe = &self.stripes[i];
+            key_guards.push(match deadline {
+                Some(deadline) => stripe.try_lock_until(deadline).ok_or_else(|| conflict(path))?,
+                None => stripe.lock(),
+            });
+        }
+        Ok(KeyGuard {
+            _keys: key_guards,
+            _shared: shared,
+            _exclusive: exclusive,
+        })
+    }
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        KeyLocks::new(DEFAULT_LOCK_TIMEOUT)
+    }
+}
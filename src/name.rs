@@ -0,0 +1,119 @@
+use crate::error::{DbResult, DbWrapError};
+use std::fmt;
+
+/// Longest accepted sub-db name, in bytes.
+pub const MAX_NAME_LEN: usize = 255;
+/// Most `/`-separated segments a sub-db name may have.
+pub const MAX_NAME_DEPTH: usize = 8;
+
+/// A validated sub-db name: a relative path of `/`-separated segments made of
+/// ASCII letters, digits, `_`, `-` and `.`, where no segment is empty, `.` or
+/// `..`. It can therefore never point outside the `DbWrap` root directory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SubDbName(String);
+
+impl SubDbName {
+    pub fn new(name: &str) -> DbResult<Self> {
+        let invalid = |reason: &str| Err(DbWrapError::InvalidPath(format!("{:?}: {}", name, reason)));
+        if name.is_empty() {
+            return invalid("name is empty");
+        }
+        if name.len() > MAX_NAME_LEN {
+            return invalid(&format!("name is longer than {} bytes", MAX_NAME_LEN));
+        }
+        if name.starts_with('/') {
+            return invalid("absolute paths are not allowed");
+        }
+        let segments: Vec<&str> = name.split('/').collect();
+        if segments.len() > MAX_NAME_DEPTH {
+            return invalid(&format!("name is deeper than {} segments", MAX_NAME_DEPTH));
+        }
+        for segment in segments {
+            match segment {
+                "" => return invalid("empty path segment"),
+                "." | ".." => return invalid("relative path segments are not allowed"),
+                _ => (),
+            }
+            if let Some(c) = segment
+                .chars()
+                .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')))
+            {
+                return invalid(&format!("character {:?} is not allowed", c));
+            }
+        }
+        Ok(SubDbName(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for SubDbName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SubDbName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid(name: &str) -> bool {
+        SubDbName::new(name).is_ok()
+    }
+
+    #[test]
+    fn accepts_relative_names() {
+        assert!(valid("users"));
+        assert!(valid("tenant-1/users_v2.idx"));
+        assert!(valid(".hidden"));
+        assert!(valid("a..b"));
+        assert_eq!(SubDbName::new("a/b").unwrap().as_str(), "a/b");
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        assert!(!valid(".."));
+        assert!(!valid("."));
+        assert!(!valid("../etc"));
+        assert!(!valid("a/../b"));
+        assert!(!valid("a/./b"));
+        assert!(!valid("a/.."));
+    }
+
+    #[test]
+    fn rejects_absolute_and_empty_segments() {
+        assert!(!valid(""));
+        assert!(!valid("/"));
+        assert!(!valid("/etc/passwd"));
+        assert!(!valid("a//b"));
+        assert!(!valid("a/"));
+    }
+
+    #[test]
+    fn rejects_other_characters() {
+        for name in &["a b", "a\\b", "a:b", "a\0b", "é", "a*b", "a\nb"] {
+            assert!(!valid(name), "{:?} accepted", name);
+        }
+    }
+
+    #[test]
+    fn length_and_depth_limits() {
+        assert!(valid(&"a".repeat(MAX_NAME_LEN)));
+        assert!(!valid(&"a".repeat(MAX_NAME_LEN + 1)));
+        assert!(valid(&vec!["a"; MAX_NAME_DEPTH].join("/")));
+        assert!(!valid(&vec!["a"; MAX_NAME_DEPTH + 1].join("/")));
+    }
+
+    #[test]
+    fn rejection_is_invalid_path() {
+        assert!(matches!(SubDbName::new(".."), Err(DbWrapError::InvalidPath(_))));
+    }
+}
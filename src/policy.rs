@@ -0,0 +1,53 @@
+/// Everything a `WritePolicy` gets to see about a pending write.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteContext<'a> {
+    /// Sub-db path as passed to `DbWrap`.
+    pub path: &'a str,
+    pub key: &'a [u8],
+    /// Existing value (header stripped) and its level, if the key exists.
+    pub old: Option<(&'a [u8], u8)>,
+    /// New value, `None` for a delete.
+    pub new: Option<&'a [u8]>,
+    pub level: u8,
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// Write the new value as is.
+    Accept,
+    /// Refuse the write, with a reason for the caller.
+    Reject(String),
+    /// Refuse the write because the existing value's level is stronger.
+    LevelConflict { existing: u8 },
+    /// Write this value instead of the new one, or instead of deleting.
+    Merge(Vec<u8>),
+}
+
+/// Decides whether a write may replace the value currently stored under a key.
+pub trait WritePolicy: Send + Sync {
+    fn admit(&self, ctx: &WriteContext) -> Admission;
+}
+
+/// Default policy: existing data can only be overwritten or deleted by an equal
+/// or lower level, unless the write is forced.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LevelPolicy;
+
+impl WritePolicy for LevelPolicy {
+    fn admit(&self, ctx: &WriteContext) -> Admission {
+        match ctx.old {
+            Some((_, old_lv)) if old_lv < ctx.level && !ctx.force => Admission::LevelConflict { existing: old_lv },
+            _ => Admission::Accept,
+        }
+    }
+}
+
+impl<F> WritePolicy for F
+where
+    F: Fn(&WriteContext) -> Admission + Send + Sync,
+{
+    fn admit(&self, ctx: &WriteContext) -> Admission {
+        self(ctx)
+    }
+}
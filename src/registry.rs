@@ -0,0 +1,270 @@
+use crate::error::{DbResult, DbWrapError};
+use parking_lot::RwLock;
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Open sub-db handles keyed by sub-db path.
+///
+/// Lookups of already-open handles only take read locks. Opening a path is
+/// coordinated per path, so a slow open only blocks callers of that path and
+/// a failed open is retried by the next caller. Only paths being opened,
+/// open or being closed by `exclusive` have a slot; closed, evicted and
+/// failed ones are dropped.
+///
+/// With a `max_open` bound, opening a path beyond it flushes and closes the
+/// least recently used handles that aren't borrowed outside the registry;
+/// they are reopened on demand.
+#[derive(Default)]
+pub(crate) struct Registry {
+    slots: RwLock<HashMap<String, Arc<Slot>>>,
+    max_open: Option<usize>,
+    read_only: bool,
+    clock: AtomicU64,
+    opens: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Default)]
+struct Slot {
+    db: RwLock<Option<Arc<DB>>>,
+    // Held exclusively while the path is opened or closed, and shared while
+    // a path nested below it is opened.
+    opening: RwLock<()>,
+    last_used: AtomicU64,
+}
+
+impl Slot {
+    fn get(&self) -> Option<Arc<DB>> {
+        self.db.read().clone()
+    }
+}
+
+/// Counters of the sub-db handle pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolMetrics {
+    /// Handles currently open.
+    pub open: usize,
+    /// Sub-db opens since start, including reopens after eviction.
+    pub opens: u64,
+    /// Handles closed to stay within the open limit.
+    pub evictions: u64,
+}
+
+impl Registry {
+    pub(crate) fn set_max_open(&mut self, max_open: Option<usize>) {
+        self.max_open = max_open;
+    }
+
+    /// Close read-only handles without flushing them.
+    pub(crate) fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    fn touch(&self, slot: &Slot) {
+        slot.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    pub(crate) fn get_or_open<F>(&self, path: &str, open: F) -> DbResult<Arc<DB>>
+    where
+        F: FnOnce() -> DbResult<DB>,
+    {
+        let mut open = Some(open);
+        loop {
+            let slot = self.slots.read().get(path).cloned();
+            if let Some(slot) = &slot {
+                if let Some(db) = slot.get() {
+                    self.touch(slot);
+                    return Ok(db);
+                }
+            }
+            let slot = match slot {
+                Some(slot) => slot,
+                None => self.slots.write().entry(path.to_string()).or_default().clone(),
+            };
+
+            // Wait for `exclusive` calls on parent paths, which would move or
+            // delete this one along with them. Slots are locked in path order,
+            // parents first.
+            let parents: Vec<Arc<Slot>> = {
+                let map = self.slots.read();
+                path.match_indices('/').filter_map(|(i, _)| map.get(&path[..i]).cloned()).collect()
+            };
+            let parent_guards: Vec<_> = parents.iter().map(|slot| slot.opening.read()).collect();
+            let opening = slot.opening.write();
+            // The slot was dropped while we waited, start over with a new one.
+            if !self.is_current(path, &slot) {
+                continue;
+            }
+            self.touch(&slot);
+            // Another caller may have finished opening while we waited.
+            if let Some(db) = slot.get() {
+                return Ok(db);
+            }
+            let open = open.take().expect("a path is opened at most once per call");
+            let db = match open() {
+                Ok(db) => Arc::new(db),
+                Err(e) => {
+                    self.remove_empty(path, &slot);
+                    return Err(e);
+                }
+            };
+            *slot.db.write() = Some(db.clone());
+            self.opens.fetch_add(1, Ordering::Relaxed);
+            drop(opening);
+            drop(parent_guards);
+            self.evict(path);
+            return Ok(db);
+        }
+    }
+
+    fn is_current(&self, path: &str, slot: &Arc<Slot>) -> bool {
+        self.slots.read().get(path).map_or(false, |current| Arc::ptr_eq(current, slot))
+    }
+
+    /// Drop `slot` from the map if it is still the one of `path` and empty.
+    /// Callers hold its opening lock, so it can't be filled concurrently.
+    fn remove_empty(&self, path: &str, slot: &Arc<Slot>) {
+        let mut slots = self.slots.write();
+        let current = slots.get(path).map_or(false, |current| Arc::ptr_eq(current, slot));
+        if current && slot.db.read().is_none() {
+            slots.remove(path);
+        }
+    }
+
+    /// Close least recently used idle handles until at most `max_open` are
+    /// open, never touching `keep`.
+    fn evict(&self, keep: &str) {
+        let max_open = match self.max_open {
+            Some(max_open) => max_open,
+            None => return,
+        };
+        let mut open: Vec<(u64, String, Arc<Slot>)> = self
+            .slots
+            .read()
+            .iter()
+            .filter(|(path, slot)| path.as_str() != keep && slot.db.read().is_some())
+            .map(|(path, slot)| (slot.last_used.load(Ordering::Relaxed), path.clone(), slot.clone()))
+            .collect();
+        // `keep` itself counts towards the limit.
+        let mut excess = (open.len() + 1).saturating_sub(max_open);
+        open.sort_by_key(|(last_used, _, _)| *last_used);
+        for (_, path, slot) in open {
+            if excess == 0 {
+                break;
+            }
+            // Skip slots being opened or closed right now.
+            let _opening = match slot.opening.try_write() {
+                Some(guard) => guard,
+                None => continue,
+            };
+            let mut handle = slot.db.write();
+            let idle = handle.as_ref().map_or(false, |db| Arc::strong_count(db) == 1);
+            if idle {
+                if let Some(db) = handle.take() {
+                    if !self.read_only {
+                        // Best effort, the memtable is persisted by the WAL anyway.
+                        let _ = db.flush();
+                    }
+                }
+                // Lookups lock the map before a handle, release it first.
+                drop(handle);
+                self.remove_empty(&path, &slot);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                excess -= 1;
+            }
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            open: self.list().len(),
+            opens: self.opens.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Paths with a currently open handle, sorted.
+    pub(crate) fn list(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self
+            .slots
+            .read()
+            .iter()
+            .filter(|(_, slot)| slot.db.read().is_some())
+            .map(|(path, _)| path.clone())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    /// Paths with a slot nested below `path`, whether open or being opened.
+    pub(crate) fn nested(&self, path: &str) -> Vec<String> {
+        let prefix = path.to_string() + "/";
+        let mut paths: Vec<String> = self.slots.read().keys().filter(|p| p.starts_with(&prefix)).cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    /// Currently open handles.
+    pub(crate) fn handles(&self) -> Vec<Arc<DB>> {
+        self.slots.read().values().filter_map(|slot| slot.get()).collect()
+    }
+
+    /// Close the handles of `paths` and run `f` while no caller can open
+    /// them or paths nested below them. Opening other paths doesn't wait.
+    ///
+    /// Fails with `DbWrapError::InUse` without closing anything if a handle is
+    /// still borrowed outside the registry.
+    pub(crate) fn exclusive<F, R>(&self, paths: &[&str], f: F) -> DbResult<R>
+    where
+        F: FnOnce() -> DbResult<R>,
+    {
+        let mut paths = paths.to_vec();
+        paths.sort_unstable();
+        paths.dedup();
+        loop {
+            // Paths without a slot get an empty one, marking them as closing
+            // for callers opening them until `f` returns.
+            let slots: Vec<Arc<Slot>> = {
+                let mut map = self.slots.write();
+                paths.iter().map(|path| map.entry(path.to_string()).or_default().clone()).collect()
+            };
+            // Taken in path order, so concurrent exclusive calls can't deadlock.
+            let opening: Vec<_> = slots.iter().map(|slot| slot.opening.write()).collect();
+            // A failed open may have dropped a slot we inserted, start over.
+            if !paths.iter().zip(&slots).all(|(path, slot)| self.is_current(path, slot)) {
+                continue;
+            }
+            let res = self.close_all(&paths, &slots).and_then(|()| f());
+            for (path, slot) in paths.iter().zip(&slots) {
+                self.remove_empty(path, slot);
+            }
+            drop(opening);
+            return res;
+        }
+    }
+
+    /// Flush and close the handles of `slots`, whose opening locks are held.
+    fn close_all(&self, paths: &[&str], slots: &[Arc<Slot>]) -> DbResult<()> {
+        // Holding every handle's write lock keeps lookups from cloning a
+        // handle between the borrow check and the close.
+        let mut handles: Vec<_> = slots.iter().map(|slot| slot.db.write()).collect();
+        for (path, handle) in paths.iter().zip(&handles) {
+            if let Some(db) = handle.as_ref() {
+                if Arc::strong_count(db) > 1 {
+                    return Err(DbWrapError::InUse(path.to_string()));
+                }
+            }
+        }
+        for handle in handles.iter_mut() {
+            if let Some(db) = handle.take() {
+                if !self.read_only {
+                    db.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
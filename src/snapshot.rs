@@ -0,0 +1,110 @@
+use crate::database::{split_level, strip_levels, LevelFilter};
+use crate::error::DbResult;
+use crate::iter::*;
+use crate::subdb::SubSnapshot;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Point-in-time view of a sub-db, see `DbWrap::snapshot`.
+///
+/// Every read sees the sub-db as it was when the snapshot was taken. The
+/// sub-db stays open, and can't be closed, while the snapshot or one of its
+/// iterators is alive.
+pub struct DbSnapshot {
+    inner: Arc<SubSnapshot>,
+}
+
+/// One read of a `DbSnapshot::read_all` list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SnapshotRead {
+    Get(Vec<u8>),
+    GetWithLevel(Vec<u8>),
+    MultiGet(Vec<Vec<u8>>),
+    GetPrefix(Vec<u8>),
+    GetPrefixWithLevel(Vec<u8>, LevelFilter),
+}
+
+/// Result of a `SnapshotRead`, in the variant of the same name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SnapshotValue {
+    Get(Option<Vec<u8>>),
+    GetWithLevel(Option<(Vec<u8>, u8)>),
+    MultiGet(Vec<Option<Vec<u8>>>),
+    GetPrefix(Vec<(Vec<u8>, Vec<u8>)>),
+    GetPrefixWithLevel(Vec<(Vec<u8>, Vec<u8>, u8)>),
+}
+
+impl DbSnapshot {
+    pub(crate) fn new(inner: SubSnapshot) -> Self {
+        DbSnapshot { inner: Arc::new(inner) }
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, k: K) -> DbResult<Option<Vec<u8>>> {
+        Ok(self.get_with_level(k)?.map(|(v, _)| v))
+    }
+
+    pub fn get_with_level<K: AsRef<[u8]>>(&self, k: K) -> DbResult<Option<(Vec<u8>, u8)>> {
+        match self.inner.db().get_opt(k.as_ref(), &self.inner.read_opts())? {
+            Some(v) => {
+                let (payload, lv) = split_level(&v)?;
+                Ok(Some((payload.to_vec(), lv)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Values of `keys` in the same order, `None` for missing keys.
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: Vec<K>) -> DbResult<Vec<Option<Vec<u8>>>> {
+        let values = self.inner.db().multi_get_opt(&keys, &self.inner.read_opts())?;
+        strip_levels(values)
+    }
+
+    pub fn get_prefix<K: AsRef<[u8]>>(&self, k: K) -> DbResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let datas = self.get_prefix_with_level(k, LevelFilter::default())?;
+        Ok(datas.into_iter().map(|(key, value, _)| (key, value)).collect())
+    }
+
+    pub fn get_prefix_with_level<K: AsRef<[u8]>>(&self, k: K, filter: LevelFilter) -> DbResult<Vec<DbEntry>> {
+        let mut datas = vec![];
+        for entry in self.iter_prefix(k, IterOptions::default()) {
+            let entry = entry?;
+            if filter.contains(entry.2) {
+                datas.push(entry);
+            }
+        }
+        Ok(datas)
+    }
+
+    pub fn iter_prefix<K: AsRef<[u8]>>(&self, prefix: K, opts: IterOptions) -> DbIter {
+        let prefix = prefix.as_ref();
+        DbIter::at_snapshot(self.inner.clone(), prefix.to_vec(), prefix_successor(prefix), opts)
+    }
+
+    pub fn iter_range<K: AsRef<[u8]>>(&self, start: K, end: K, opts: IterOptions) -> DbIter {
+        DbIter::at_snapshot(
+            self.inner.clone(),
+            start.as_ref().to_vec(),
+            Some(end.as_ref().to_vec()),
+            opts,
+        )
+    }
+
+    /// Run every read of `reads` against the snapshot, stopping at the first
+    /// error.
+    pub fn read_all(&self, reads: Vec<SnapshotRead>) -> DbResult<Vec<SnapshotValue>> {
+        reads
+            .into_iter()
+            .map(|read| {
+                Ok(match read {
+                    SnapshotRead::Get(key) => SnapshotValue::Get(self.get(key)?),
+                    SnapshotRead::GetWithLevel(key) => SnapshotValue::GetWithLevel(self.get_with_level(key)?),
+                    SnapshotRead::MultiGet(keys) => SnapshotValue::MultiGet(self.multi_get(keys)?),
+                    SnapshotRead::GetPrefix(key) => SnapshotValue::GetPrefix(self.get_prefix(key)?),
+                    SnapshotRead::GetPrefixWithLevel(key, filter) => {
+                        SnapshotValue::GetPrefixWithLevel(self.get_prefix_with_level(key, filter)?)
+                    }
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,287 @@
+use crate::error::DbResult;
+use parking_lot::{Mutex, RwLock};
+use rocksdb::{
+    BoundColumnFamily, DBRawIteratorWithThreadMode, DBWithThreadMode, MultiThreaded, Options, ReadOptions,
+    SnapshotWithThreadMode, WriteBatch, DB,
+};
+use std::io;
+use std::sync::Arc;
+
+/// RocksDB instance holding one column family per sub-db.
+pub(crate) type CfDb = DBWithThreadMode<MultiThreaded>;
+
+/// Handle on one sub-db: either its own `DB` or a column family of the
+/// shared instance.
+#[derive(Clone)]
+pub(crate) enum SubDb {
+    Db(Arc<DB>),
+    Cf(Arc<CfDb>, String),
+}
+
+/// Write batch targeting a single sub-db, see `SubDb::batch`.
+pub(crate) struct SubBatch<'a> {
+    inner: WriteBatch,
+    cf: Option<Arc<BoundColumnFamily<'a>>>,
+}
+
+/// Raw iterator over a single sub-db.
+pub(crate) enum SubIter<'a> {
+    Db(DBRawIteratorWithThreadMode<'a, DB>),
+    Cf(DBRawIteratorWithThreadMode<'a, CfDb>),
+}
+
+/// RocksDB snapshot of a sub-db, owning the handle it was taken from.
+pub(crate) struct SubSnapshot {
+    // Declared before `db` so it is released before the handle it borrows.
+    snapshot: SnapshotKind,
+    db: SubDb,
+}
+
+enum SnapshotKind {
+    Db(SnapshotWithThreadMode<'static, DB>),
+    Cf(SnapshotWithThreadMode<'static, CfDb>),
+}
+
+/// Lazily opened shared instance of column-family mode.
+#[derive(Default)]
+pub(crate) struct CfStore {
+    db: RwLock<Option<Arc<CfDb>>>,
+    // Serializes opening the instance and creating column families.
+    lock: Mutex<()>,
+}
+
+pub(crate) fn column_family<'a>(db: &'a CfDb, name: &str) -> DbResult<Arc<BoundColumnFamily<'a>>> {
+    db.cf_handle(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("column family {} not found", name)).into())
+}
+
+impl SubDb {
+    pub(crate) fn get(&self, key: &[u8]) -> DbResult<Option<Vec<u8>>> {
+        self.get_opt(key, &ReadOptions::default())
+    }
+
+    pub(crate) fn get_opt(&self, key: &[u8], opts: &ReadOptions) -> DbResult<Option<Vec<u8>>> {
+        match self {
+            SubDb::Db(db) => Ok(db.get_opt(key, opts)?),
+            SubDb::Cf(db, name) => Ok(db.get_cf_opt(&column_family(db, name)?, key, opts)?),
+        }
+    }
+
+    /// Read every key of `keys` in one batched lookup.
+    pub(crate) fn multi_get_opt<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+        opts: &ReadOptions,
+    ) -> DbResult<Vec<Option<Vec<u8>>>> {
+        let values = match self {
+            SubDb::Db(db) => db.multi_get_opt(keys.iter().map(|k| k.as_ref()), opts),
+            SubDb::Cf(db, name) => {
+                let cf = column_family(db, name)?;
+                db.multi_get_cf_opt(keys.iter().map(|k| (&cf, k.as_ref())), opts)
+            }
+        };
+        values.into_iter().map(|v| Ok(v?)).collect()
+    }
+
+    pub(crate) fn batch(&self) -> DbResult<SubBatch<'_>> {
+        let cf = match self {
+            SubDb::Db(_) => None,
+            SubDb::Cf(db, name) => Some(column_family(db, name)?),
+        };
+        Ok(SubBatch {
+            inner: WriteBatch::default(),
+            cf,
+        })
+    }
+
+    pub(crate) fn write(&self, batch: SubBatch<'_>) -> DbResult<()> {
+        match self {
+            SubDb::Db(db) => db.write(batch.inner)?,
+            SubDb::Cf(db, _) => db.write(batch.inner)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self, key: &[u8]) -> DbResult<()> {
+        match self {
+            SubDb::Db(db) => db.delete(key)?,
+            SubDb::Cf(db, name) => db.delete_cf(&column_family(db, name)?, key)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&self) -> DbResult<()> {
+        match self {
+            SubDb::Db(db) => db.flush()?,
+            SubDb::Cf(db, name) => db.flush_cf(&column_family(db, name)?)?,
+        }
+        Ok(())
+    }
+
+    /// Replay the primary's latest writes, for secondary instances.
+    pub(crate) fn catch_up(&self) -> DbResult<()> {
+        match self {
+            SubDb::Db(db) => db.try_catch_up_with_primary()?,
+            SubDb::Cf(db, _) => db.try_catch_up_with_primary()?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn raw_iter(&self, opts: ReadOptions) -> DbResult<SubIter<'_>> {
+        match self {
+            SubDb::Db(db) => Ok(SubIter::Db(db.raw_iterator_opt(opts))),
+            SubDb::Cf(db, name) => Ok(SubIter::Cf(db.raw_iterator_cf_opt(&column_family(db, name)?, opts))),
+        }
+    }
+}
+
+impl SubSnapshot {
+    pub(crate) fn new(db: SubDb) -> Self {
+        // SAFETY: the snapshots borrow the instance behind the `Arc` held in
+        // `db`, which outlives them since `snapshot` is dropped first and the
+        // instance never moves.
+        let snapshot = match &db {
+            SubDb::Db(inner) => {
+                let inner: &'static DB = unsafe { &*Arc::as_ptr(inner) };
+                SnapshotKind::Db(inner.snapshot())
+            }
+            SubDb::Cf(inner, _) => {
+                let inner: &'static CfDb = unsafe { &*Arc::as_ptr(inner) };
+                SnapshotKind::Cf(inner.snapshot())
+            }
+        };
+        SubSnapshot { snapshot, db }
+    }
+
+    pub(crate) fn db(&self) -> &SubDb {
+        &self.db
+    }
+
+    /// Read options bound to the snapshot; they must not outlive it.
+    pub(crate) fn read_opts(&self) -> ReadOptions {
+        let mut opts = ReadOptions::default();
+        match &self.snapshot {
+            SnapshotKind::Db(snapshot) => opts.set_snapshot(snapshot),
+            SnapshotKind::Cf(snapshot) => opts.set_snapshot(snapshot),
+        }
+        opts
+    }
+}
+
+impl<'a> SubBatch<'a> {
+    pub(crate) fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        match &self.cf {
+            Some(cf) => self.inner.put_cf(cf, key, value),
+            None => self.inner.put(key, value),
+        }
+    }
+
+    pub(crate) fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+        match &self.cf {
+            Some(cf) => self.inner.delete_cf(cf, key),
+            None => self.inner.delete(key),
+        }
+    }
+
+    pub(crate) fn delete_range<K: AsRef<[u8]>>(&mut self, from: K, to: K) {
+        match &self.cf {
+            Some(cf) => self.inner.delete_range_cf(cf, from, to),
+            None => self.inner.delete_range(from, to),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $iter:ident => $e:expr) => {
+        match $self {
+            SubIter::Db($iter) => $e,
+            SubIter::Cf($iter) => $e,
+        }
+    };
+}
+
+impl<'a> SubIter<'a> {
+    pub(crate) fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        dispatch!(self, iter => iter.seek(key))
+    }
+
+    pub(crate) fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+        dispatch!(self, iter => iter.seek_for_prev(key))
+    }
+
+    pub(crate) fn seek_to_last(&mut self) {
+        dispatch!(self, iter => iter.seek_to_last())
+    }
+
+    pub(crate) fn next(&mut self) {
+        dispatch!(self, iter => iter.next())
+    }
+
+    pub(crate) fn prev(&mut self) {
+        dispatch!(self, iter => iter.prev())
+    }
+
+    pub(crate) fn key(&self) -> Option<&[u8]> {
+        dispatch!(self, iter => iter.key())
+    }
+
+    pub(crate) fn item(&self) -> Option<(&[u8], &[u8])> {
+        dispatch!(self, iter => iter.item())
+    }
+
+    pub(crate) fn status(&self) -> DbResult<()> {
+        Ok(dispatch!(self, iter => iter.status())?)
+    }
+}
+
+impl CfStore {
+    pub(crate) fn get(&self) -> Option<Arc<CfDb>> {
+        self.db.read().clone()
+    }
+
+    pub(crate) fn get_or_open<F>(&self, open: F) -> DbResult<Arc<CfDb>>
+    where
+        F: FnOnce() -> DbResult<CfDb>,
+    {
+        if let Some(db) = self.get() {
+            return Ok(db);
+        }
+        let _lock = self.lock.lock();
+        if let Some(db) = self.get() {
+            return Ok(db);
+        }
+        let db = Arc::new(open()?);
+        *self.db.write() = Some(db.clone());
+        Ok(db)
+    }
+
+    /// Create the column family `name` in `db` unless it already exists.
+    pub(crate) fn ensure_cf(&self, db: &CfDb, name: &str, opts: &Options) -> DbResult<()> {
+        if db.cf_handle(name).is_some() {
+            return Ok(());
+        }
+        let _lock = self.lock.lock();
+        if db.cf_handle(name).is_none() {
+            db.create_cf(name, opts)?;
+        }
+        Ok(())
+    }
+
+    /// Drop the column family `name`, returning whether it existed.
+    pub(crate) fn drop_cf(&self, db: &CfDb, name: &str) -> DbResult<bool> {
+        let _lock = self.lock.lock();
+        if db.cf_handle(name).is_none() {
+            return Ok(false);
+        }
+        db.drop_cf(name)?;
+        Ok(true)
+    }
+}